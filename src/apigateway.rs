@@ -0,0 +1,103 @@
+use anyhow::anyhow;
+use serde_json::{json, Value};
+use std::string::ToString;
+
+use crate::ErrorCode;
+
+/// Whether the event comes from an API Gateway Lambda proxy integration, which wraps the request
+/// into a string `body`. The envelope is recognised even without a body, e.g. for GET requests,
+/// so that those get a proper 400 response.
+pub fn is_proxy_event(ev: &Value) -> bool {
+    ev.get("requestContext")
+        .map(Value::is_object)
+        .unwrap_or(false)
+        || ev.get("httpMethod").map(Value::is_string).unwrap_or(false)
+}
+
+/// Decodes the request from the proxy event's `body`, which is Base64-encoded when
+/// `isBase64Encoded` is set.
+pub fn request(ev: &Value) -> anyhow::Result<Value> {
+    let body = ev
+        .get("body")
+        .and_then(Value::as_str)
+        .ok_or_else(|| anyhow!("Missing request body, expected a JSON request"))?;
+    let body = if ev.get("isBase64Encoded").and_then(Value::as_bool) == Some(true) {
+        base64::decode(body).map_err(|e| anyhow!("Invalid Base64 body: {}", e.to_string()))?
+    } else {
+        body.as_bytes().to_vec()
+    };
+    serde_json::from_slice(&body).map_err(|e| anyhow!("Invalid JSON body: {}", e.to_string()))
+}
+
+/// Wraps the response for API Gateway, with a status code matching its `errorCode`.
+pub fn response(response: &Value) -> anyhow::Result<Value> {
+    let status_code = match response.get("success").and_then(Value::as_bool) {
+        Some(false) => response
+            .get("errorCode")
+            .and_then(|code| serde_json::from_value(code.clone()).ok())
+            .map(status_code)
+            .unwrap_or(500),
+        _ => 200,
+    };
+    let body = serde_json::to_string(response)
+        .map_err(|e| anyhow!("Failed to serialise response: {}", e.to_string()))?;
+    Ok(json!({
+        "statusCode": status_code,
+        "headers": {"Content-Type": "application/json"},
+        "isBase64Encoded": false,
+        "body": body,
+    }))
+}
+
+fn status_code(code: ErrorCode) -> u16 {
+    match code {
+        ErrorCode::InvalidRequest => 400,
+        ErrorCode::RenderFailed => 422,
+        ErrorCode::UploadFailed => 502,
+        ErrorCode::Timeout => 504,
+        ErrorCode::TooBusy => 503,
+        ErrorCode::Internal => 500,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn proxy_events_are_recognised_without_a_body() {
+        assert!(is_proxy_event(&json!({"httpMethod": "GET", "body": null})));
+        assert!(is_proxy_event(&json!({"requestContext": {}, "body": "{}"})));
+        assert!(!is_proxy_event(&json!({"pages": [], "body": "x"})));
+        assert!(!is_proxy_event(&json!({"documents": []})));
+    }
+
+    #[test]
+    fn missing_bodies_are_invalid_requests() {
+        for ev in &[
+            json!({"httpMethod": "GET", "body": null}),
+            json!({"requestContext": {}}),
+        ] {
+            assert_eq!(
+                request(ev).unwrap_err().to_string(),
+                "Missing request body, expected a JSON request"
+            );
+        }
+        assert_eq!(
+            request(&json!({
+                "requestContext": {},
+                "isBase64Encoded": true,
+                "body": base64::encode(r#"{"pages": []}"#),
+            }))
+            .unwrap(),
+            json!({"pages": []})
+        );
+    }
+
+    #[test]
+    fn invalid_requests_respond_with_400() {
+        let response =
+            response(&json!({"success": false, "errorCode": "invalid_request"})).unwrap();
+        assert_eq!(response["statusCode"], 400);
+    }
+}
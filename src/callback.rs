@@ -0,0 +1,69 @@
+use anyhow::anyhow;
+use hyper::{Body, Client, Request};
+use hyper_rustls::HttpsConnector;
+use serde_json::Value;
+use std::env;
+use std::string::ToString;
+use std::time::Duration;
+
+use crate::urls::validate_url;
+use crate::utils::runtime;
+#[allow(unused_imports)]
+use crate::{debug, error, info, warn};
+use crate::{PdfRequest, PdfResponse};
+
+const DEFAULT_CALLBACK_TIMEOUT_MS: u64 = 5000;
+
+/// POSTs the response to `callbackUrl`, without the inline PDF. Failures are only logged, as the
+/// conversion itself is done either way.
+pub fn notify(ev: &PdfRequest, response: &PdfResponse) {
+    if let Some(callback_url) = &ev.callback_url {
+        match post(ev, callback_url, response) {
+            Ok(()) => {
+                info!("Notified {}", callback_url);
+            }
+            Err(e) => {
+                warn!("Failed to notify {}: {}", callback_url, e);
+            }
+        }
+    }
+}
+
+fn post(ev: &PdfRequest, callback_url: &str, response: &PdfResponse) -> anyhow::Result<()> {
+    validate_url(callback_url, ev.allow_private_urls)?;
+    let timeout = match env::var("CALLBACK_TIMEOUT_MS") {
+        Ok(timeout) => timeout
+            .parse::<u64>()
+            .map_err(|e| anyhow!("Invalid CALLBACK_TIMEOUT_MS: {}", e.to_string()))?,
+        Err(_) => DEFAULT_CALLBACK_TIMEOUT_MS,
+    };
+
+    let mut body = serde_json::to_value(response)
+        .map_err(|e| anyhow!("Failed to serialise response: {}", e.to_string()))?;
+    if let Value::Object(fields) = &mut body {
+        fields.remove("pdfBase64");
+        if let Some(output) = ev.output() {
+            fields
+                .entry("objectKey")
+                .or_insert_with(|| Value::String(output.object_key.clone()));
+        }
+    }
+    let request = Request::post(callback_url)
+        .header("Content-Type", "application/json")
+        .body(Body::from(body.to_string()))
+        .map_err(|e| anyhow!("Invalid callback request: {}", e.to_string()))?;
+
+    let mut runtime = runtime()?;
+    let status = runtime.block_on(async {
+        let client = Client::builder().build::<_, Body>(HttpsConnector::new());
+        match tokio::time::timeout(Duration::from_millis(timeout), client.request(request)).await {
+            Ok(Ok(response)) => Ok(response.status()),
+            Ok(Err(e)) => Err(anyhow!(e.to_string())),
+            Err(_) => Err(anyhow!("Timed out after {}ms", timeout)),
+        }
+    })?;
+    if !status.is_success() {
+        return Err(anyhow!("Callback responded with {}", status));
+    }
+    Ok(())
+}
@@ -0,0 +1,142 @@
+use anyhow::anyhow;
+use once_cell::sync::Lazy;
+use serde::Deserialize;
+use std::env;
+use std::string::ToString;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::options::merge_defaults;
+use crate::s3::{download_all, parse_uri};
+#[allow(unused_imports)]
+use crate::{debug, error, info, warn};
+use crate::{PdfOption, PdfRequest, S3Object};
+
+/// When the shared config was last fetched, and the config unless that failed.
+type CachedConfig = (Instant, Option<SharedConfig>);
+
+static CONFIG: Lazy<Mutex<Option<CachedConfig>>> = Lazy::new(|| Mutex::new(None));
+
+/// How long a failed fetch is remembered before the config is fetched again.
+const RETRY_AFTER: Duration = Duration::from_secs(30);
+
+/// House-style defaults in `CONFIG_S3_URI`, which every request's own values override.
+#[derive(Deserialize, Clone, Default)]
+struct SharedConfig {
+    #[serde(default = "Vec::new")]
+    options: Vec<PdfOption>,
+    #[serde(rename = "defaultPageOptions", default = "Vec::new")]
+    default_page_options: Vec<PdfOption>,
+    #[serde(rename = "injectCssBase64")]
+    inject_css_base64: Option<String>,
+}
+
+/// Merges the shared config under the request. The config is fetched once per container, or
+/// again after `CONFIG_TTL_SECONDS`. If it can't be fetched, requests go on without it until it's
+/// fetched again after `RETRY_AFTER`.
+pub fn apply(ev: &mut PdfRequest) {
+    let uri = match env::var("CONFIG_S3_URI") {
+        Ok(uri) => uri,
+        Err(_) => return,
+    };
+    let config = {
+        let mut cached = CONFIG.lock().unwrap_or_else(|e| e.into_inner());
+        let ttl = match env::var("CONFIG_TTL_SECONDS").map(|ttl| ttl.parse::<u64>()) {
+            Ok(Ok(ttl)) => Some(Duration::from_secs(ttl)),
+            Ok(Err(e)) => {
+                warn!("Invalid CONFIG_TTL_SECONDS, caching forever: {}", e);
+                None
+            }
+            Err(_) => None,
+        };
+        let expired = match &*cached {
+            None => true,
+            Some((fetched, config)) => is_expired(fetched.elapsed(), config.is_some(), ttl),
+        };
+        if expired {
+            let config = match fetch(&uri) {
+                Ok(config) => Some(config),
+                Err(e) => {
+                    error!("Failed to load shared config from {}: {}", uri, e);
+                    None
+                }
+            };
+            *cached = Some((Instant::now(), config));
+        }
+        cached
+            .as_ref()
+            .and_then(|(_, config)| config.clone())
+            .unwrap_or_default()
+    };
+
+    ev.options = merge_defaults(&config.options, &ev.options);
+    ev.default_page_options =
+        merge_defaults(&config.default_page_options, &ev.default_page_options);
+    if ev.inject_css_base64.is_none() {
+        ev.inject_css_base64 = config.inject_css_base64;
+    }
+}
+
+/// Loaded configs expire after the TTL, if any. Failed fetches are retried after `RETRY_AFTER`, or
+/// the TTL if that's shorter, so that an outage doesn't disable the config for good but S3 isn't
+/// hit by every request either.
+fn is_expired(age: Duration, loaded: bool, ttl: Option<Duration>) -> bool {
+    let lifetime = if loaded {
+        ttl
+    } else {
+        Some(ttl.map_or(RETRY_AFTER, |ttl| ttl.min(RETRY_AFTER)))
+    };
+    lifetime.is_some_and(|lifetime| age >= lifetime)
+}
+
+fn fetch(uri: &str) -> anyhow::Result<SharedConfig> {
+    let (bucket, object_key) = parse_uri(uri)?;
+    let s3_object = S3Object {
+        region: None,
+        endpoint: None,
+        bucket,
+        object_key,
+    };
+    let contents = download_all(&[&s3_object])?.pop().unwrap_or_default();
+    let config = serde_json::from_slice(&contents)
+        .map_err(|e| anyhow!("Invalid shared config: {}", e.to_string()))?;
+    info!("Loaded shared config from {}", uri);
+    Ok(config)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn loaded_config_expires_after_ttl_only() {
+        let day = Duration::from_secs(86400);
+        assert!(!is_expired(day, true, None));
+        assert!(!is_expired(
+            Duration::from_secs(59),
+            true,
+            Some(Duration::from_secs(60))
+        ));
+        assert!(is_expired(
+            Duration::from_secs(60),
+            true,
+            Some(Duration::from_secs(60))
+        ));
+    }
+
+    #[test]
+    fn failed_fetch_is_retried() {
+        assert!(!is_expired(Duration::from_secs(29), false, None));
+        assert!(is_expired(RETRY_AFTER, false, None));
+        assert!(is_expired(
+            RETRY_AFTER,
+            false,
+            Some(Duration::from_secs(3600))
+        ));
+        assert!(is_expired(
+            Duration::from_secs(5),
+            false,
+            Some(Duration::from_secs(5))
+        ));
+    }
+}
@@ -0,0 +1,42 @@
+use std::fmt;
+
+use crate::ErrorCode;
+
+/// An error tagged with the code reported back to the caller.
+#[derive(Debug)]
+pub struct CodedError {
+    pub code: ErrorCode,
+    pub error: anyhow::Error,
+}
+
+impl fmt::Display for CodedError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.error)
+    }
+}
+
+impl std::error::Error for CodedError {}
+
+pub trait WithErrorCode<T> {
+    /// Tags the error with a code, unless a more specific one has already been attached.
+    fn code(self, code: ErrorCode) -> anyhow::Result<T>;
+}
+
+impl<T> WithErrorCode<T> for anyhow::Result<T> {
+    fn code(self, code: ErrorCode) -> anyhow::Result<T> {
+        self.map_err(|error| {
+            if error.is::<CodedError>() {
+                error
+            } else {
+                CodedError { code, error }.into()
+            }
+        })
+    }
+}
+
+pub fn error_code(error: &anyhow::Error) -> ErrorCode {
+    error
+        .downcast_ref::<CodedError>()
+        .map(|e| e.code)
+        .unwrap_or(ErrorCode::Internal)
+}
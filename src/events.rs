@@ -0,0 +1,174 @@
+use anyhow::anyhow;
+use percent_encoding::percent_decode_str;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::env;
+use std::string::ToString;
+
+use crate::s3::{escape_key_template, expand_key_template};
+use crate::PdfRequest;
+#[allow(unused_imports)]
+use crate::{debug, error, info, warn};
+
+const DEFAULT_OUTPUT_KEY_TEMPLATE: &str = "{name}.pdf";
+
+#[derive(Deserialize)]
+struct S3Event {
+    #[serde(rename = "Records")]
+    records: Vec<Record>,
+}
+
+#[derive(Deserialize)]
+struct Record {
+    #[serde(rename = "awsRegion")]
+    aws_region: Option<String>,
+    s3: RecordS3,
+}
+
+#[derive(Deserialize)]
+struct RecordS3 {
+    bucket: RecordBucket,
+    object: RecordObject,
+}
+
+#[derive(Deserialize)]
+struct RecordBucket {
+    name: String,
+}
+
+#[derive(Deserialize)]
+struct RecordObject {
+    key: String,
+}
+
+/// Whether the event is an S3 notification, i.e. `Records` from `aws:s3`.
+pub fn is_s3_event(ev: &Value) -> bool {
+    ev.get("Records")
+        .and_then(Value::as_array)
+        .and_then(|records| records.first())
+        .and_then(|record| record.get("eventSource"))
+        .and_then(Value::as_str)
+        == Some("aws:s3")
+}
+
+/// Turns each record of an S3 notification into a single page request of the uploaded object.
+/// Objects which aren't `.html` or `.htm` are skipped, i.e. `None`.
+pub fn requests(ev: Value) -> anyhow::Result<Vec<anyhow::Result<Option<PdfRequest>>>> {
+    let event: S3Event =
+        serde_json::from_value(ev).map_err(|e| anyhow!("Invalid S3 event: {}", e.to_string()))?;
+    Ok(event.records.into_iter().map(request).collect())
+}
+
+/// Keys in S3 notifications are URL-encoded, with spaces as `+`. Unlike a query string, `=` and
+/// `&` are part of the key.
+fn decode_key(key: &str) -> String {
+    percent_decode_str(&key.replace('+', " "))
+        .decode_utf8_lossy()
+        .into_owned()
+}
+
+fn request(record: Record) -> anyhow::Result<Option<PdfRequest>> {
+    let key = decode_key(&record.s3.object.key);
+    let name = match html_name(&key) {
+        Some(name) => name,
+        None => {
+            info!(
+                "Skipping s3://{}/{}, not an HTML object",
+                record.s3.bucket.name, key
+            );
+            return Ok(None);
+        }
+    };
+    let bucket =
+        env::var("S3_EVENT_OUTPUT_BUCKET").unwrap_or_else(|_| record.s3.bucket.name.clone());
+    let template = env::var("S3_EVENT_OUTPUT_KEY_TEMPLATE")
+        .unwrap_or_else(|_| DEFAULT_OUTPUT_KEY_TEMPLATE.to_owned());
+    let object_key = output_key(&template, name)?;
+    if bucket == record.s3.bucket.name && object_key == key {
+        return Err(anyhow!("Output of {} would overwrite itself", key));
+    }
+    info!(
+        "Rendering s3://{}/{} to s3://{}/{}",
+        record.s3.bucket.name, key, bucket, object_key
+    );
+    serde_json::from_value(json!({
+        "pages": [{
+            "type": "PAGE",
+            "htmlS3": {
+                "region": record.aws_region,
+                "bucket": record.s3.bucket.name,
+                "objectKey": key,
+            },
+        }],
+        "output": {
+            "region": record.aws_region,
+            "bucket": bucket,
+            "objectKey": escape_key_template(&object_key),
+        },
+    }))
+    .map(Some)
+    .map_err(|e| anyhow!("Failed to build request: {}", e.to_string()))
+}
+
+/// Expands the template's own placeholders, substituting `{name}` last so that braces in the
+/// source key are kept as they are.
+fn output_key(template: &str, name: &str) -> anyhow::Result<String> {
+    let pieces = template
+        .split("{name}")
+        .map(|piece| expand_key_template(piece, "pdf"))
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    Ok(pieces.join(name))
+}
+
+/// The key without its `.html` or `.htm` extension.
+fn html_name(key: &str) -> Option<&str> {
+    let lower = key.to_ascii_lowercase();
+    [".html", ".htm"]
+        .iter()
+        .find(|extension| lower.ends_with(*extension))
+        .map(|extension| &key[..key.len() - extension.len()])
+        .filter(|name| !name.is_empty() && !name.ends_with('/'))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decode_key_keeps_query_characters() {
+        assert_eq!(
+            decode_key("tenant%3Dacme/my+report%26co.html"),
+            "tenant=acme/my report&co.html"
+        );
+        assert_eq!(
+            decode_key("tenant=acme/report.html"),
+            "tenant=acme/report.html"
+        );
+    }
+
+    fn record(key: &str) -> Record {
+        serde_json::from_value(json!({
+            "awsRegion": "eu-west-2",
+            "s3": {"bucket": {"name": "bucket"}, "object": {"key": key}},
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn braces_in_source_keys_are_kept() {
+        assert_eq!(output_key("{name}.pdf", "in/a{b").unwrap(), "in/a{b.pdf");
+        assert_eq!(
+            output_key("{name}.pdf", "in/{uuid}").unwrap(),
+            "in/{uuid}.pdf"
+        );
+        assert_eq!(
+            output_key("out/{name}.{ext}", "in/{date}").unwrap(),
+            "out/in/{date}.pdf"
+        );
+        assert!(output_key("{name}.{bogus}", "in/a").is_err());
+
+        let mut ev = request(record("in/a%7Bb.html")).unwrap().unwrap();
+        crate::wkhtmltopdf::resolve_object_key(&mut ev).unwrap();
+        assert_eq!(ev.output().unwrap().object_key, "in/a{b.pdf");
+    }
+}
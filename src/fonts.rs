@@ -0,0 +1,236 @@
+use anyhow::anyhow;
+use once_cell::sync::OnceCell;
+use std::env;
+use std::fs;
+use std::io::ErrorKind;
+use std::path::{Component, Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::string::ToString;
+use std::time::Instant;
+
+use crate::installation::Installation;
+use crate::metrics::millis;
+use crate::s3::{download_all_to, list_objects, parse_uri};
+use crate::utils::temp_dir;
+use crate::S3Object;
+#[allow(unused_imports)]
+use crate::{debug, error, info, warn};
+
+static FONTCONFIG_PATH: OnceCell<String> = OnceCell::new();
+
+/// The fontconfig directory to render with. With `FONTS_S3_PREFIX`, the fonts under it are
+/// downloaded on the first render of the container and added to the installation's. Failed
+/// downloads are retried by the next render.
+pub fn fontconfig_path(installation: &Installation) -> anyhow::Result<String> {
+    let uri = match env::var("FONTS_S3_PREFIX") {
+        Ok(uri) => uri,
+        Err(_) => return Ok(installation.fontconfig_path.clone()),
+    };
+    FONTCONFIG_PATH
+        .get_or_try_init(|| fetch(installation, &uri))
+        .cloned()
+        .map_err(|e| anyhow!("Failed to fetch fonts from {}: {}", uri, e))
+}
+
+/// Builds the font cache at cold start unless WARM_FONT_CACHE=0, so that the first render doesn't
+/// wait for fontconfig to scan the fonts. With `FONTS_S3_PREFIX`, the fonts are fetched as well.
+/// Failures are only logged, and renders still work without a cache.
+pub fn warm_cache() {
+    if env::var("WARM_FONT_CACHE")
+        .map(|v| v == "0")
+        .unwrap_or(false)
+    {
+        return;
+    }
+    let start = Instant::now();
+    let installation = match Installation::resolve() {
+        Ok(installation) => installation,
+        Err(e) => {
+            warn!("Skipped warming the font cache: {}", e);
+            return;
+        }
+    };
+    if env::var("FONTS_S3_PREFIX").is_ok() {
+        // fetching the fonts builds their cache too
+        if let Err(e) = fontconfig_path(&installation) {
+            warn!("{}", e);
+            return;
+        }
+    } else {
+        build_cache(&installation, &installation.fontconfig_path);
+    }
+    info!("Warming the font cache took {} ms", millis(start.elapsed()));
+}
+
+fn fetch(installation: &Installation, uri: &str) -> anyhow::Result<String> {
+    let (bucket, prefix) = parse_uri(uri)?;
+    let region = env::var("FONTS_S3_REGION").ok();
+    let config_dir = temp_dir().join("wkhtmltopdf-fontconfig");
+    let fonts_dir = config_dir.join("fonts");
+    let mut s3_objects = Vec::new();
+    let mut paths = Vec::new();
+    for object_key in list_objects(region.as_deref(), &bucket, &prefix)? {
+        if object_key.ends_with('/') {
+            continue;
+        }
+        // keeping the keys' directories avoids fonts of the same name overwriting each other
+        let path = fonts_dir.join(font_path(&prefix, &object_key)?);
+        s3_objects.push(S3Object {
+            region: region.clone(),
+            endpoint: None,
+            bucket: bucket.clone(),
+            object_key,
+        });
+        paths.push(path);
+    }
+    for dir in paths.iter().filter_map(|path| path.parent()) {
+        fs::create_dir_all(dir)
+            .map_err(|e| anyhow!("Failed to create {}: {}", dir.display(), e.to_string()))?;
+    }
+    fs::create_dir_all(&fonts_dir).map_err(|e| {
+        anyhow!(
+            "Failed to create {}: {}",
+            fonts_dir.display(),
+            e.to_string()
+        )
+    })?;
+    download_all_to(
+        &s3_objects
+            .iter()
+            .zip(&paths)
+            .map(|(s3_object, path)| (s3_object, path.as_path()))
+            .collect::<Vec<_>>(),
+    )?;
+
+    let config = format!(
+        r#"<?xml version="1.0"?>
+<!DOCTYPE fontconfig SYSTEM "fonts.dtd">
+<fontconfig>
+  <include ignore_missing="yes">{}/fonts.conf</include>
+  <dir>{}</dir>
+  <cachedir>{}</cachedir>
+</fontconfig>
+"#,
+        installation.fontconfig_path,
+        fonts_dir.display(),
+        config_dir.join("cache").display()
+    );
+    let config_path = config_dir.join("fonts.conf");
+    fs::write(&config_path, config).map_err(|e| {
+        anyhow!(
+            "Failed to write {}: {}",
+            config_path.display(),
+            e.to_string()
+        )
+    })?;
+    info!(
+        "Fetched {} fonts from {} into {}",
+        s3_objects.len(),
+        uri,
+        fonts_dir.display()
+    );
+
+    let config_dir = config_dir.to_string_lossy().to_string();
+    build_cache(installation, &config_dir);
+    Ok(config_dir)
+}
+
+/// Where a font is stored under the fonts directory, i.e. its key relative to the prefix.
+/// fontconfig scans the directories recursively.
+fn font_path(prefix: &str, object_key: &str) -> anyhow::Result<PathBuf> {
+    let relative = object_key
+        .strip_prefix(prefix)
+        .unwrap_or(object_key)
+        .trim_start_matches('/');
+    let path = Path::new(relative);
+    if relative.is_empty()
+        || !path
+            .components()
+            .all(|component| matches!(component, Component::Normal(_)))
+    {
+        return Err(anyhow!("Invalid font key {}", object_key));
+    }
+    Ok(path.to_path_buf())
+}
+
+/// Warms the font cache with fc-cache, if there's one. fontconfig builds the cache itself
+/// otherwise, only slower.
+fn build_cache(installation: &Installation, fontconfig_path: &str) {
+    let fc_cache = installation
+        .sibling("fc-cache")
+        .unwrap_or_else(|| "fc-cache".to_owned());
+    match cache_command(&fc_cache, fontconfig_path).output() {
+        Ok(output) if output.status.success() => {
+            info!("Built font cache with {}", fc_cache);
+            for line in String::from_utf8_lossy(&output.stderr)
+                .lines()
+                .map(str::trim)
+                .filter(|line| !line.is_empty())
+            {
+                warn!("{}: {}", fc_cache, line);
+            }
+        }
+        Ok(output) => {
+            warn!(
+                "{} exited with {}: {}",
+                fc_cache,
+                output.status,
+                String::from_utf8_lossy(&output.stderr).trim()
+            );
+        }
+        Err(e) if e.kind() == ErrorKind::NotFound => {
+            debug!("No fc-cache found");
+        }
+        Err(e) => {
+            warn!("Failed to run {}: {}", fc_cache, e);
+        }
+    }
+}
+
+/// fc-cache with the same `FONTCONFIG_PATH` as wkhtmltopdf. `-f` rebuilds caches which look up
+/// to date, e.g. ones shipped in a layer with other mtimes, and `-s` skips the home directory,
+/// which isn't writable in Lambda.
+fn cache_command(fc_cache: &str, fontconfig_path: &str) -> Command {
+    let mut command = Command::new(fc_cache);
+    command
+        .args(["-f", "-s"])
+        .env("FONTCONFIG_PATH", fontconfig_path)
+        .stdin(Stdio::null());
+    command
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::ffi::OsStr;
+
+    #[test]
+    fn font_paths_keep_directories_under_the_prefix() {
+        assert_eq!(
+            font_path("fonts/", "fonts/a/Brand.ttf").unwrap(),
+            Path::new("a/Brand.ttf")
+        );
+        assert_eq!(
+            font_path("fonts", "fonts/b/Brand.ttf").unwrap(),
+            Path::new("b/Brand.ttf")
+        );
+        assert_eq!(font_path("", "Brand.ttf").unwrap(), Path::new("Brand.ttf"));
+        for key in &["fonts/", "fonts/../etc/passwd", "fonts/a/../../b.ttf"] {
+            assert!(font_path("fonts/", key).is_err(), "{} was accepted", key);
+        }
+    }
+
+    #[test]
+    fn cache_command_forces_the_configured_cache() {
+        let command = cache_command("/opt/bin/fc-cache", "/tmp/wkhtmltopdf-fontconfig");
+        assert_eq!(command.get_program(), "/opt/bin/fc-cache");
+        assert_eq!(command.get_args().collect::<Vec<_>>(), vec!["-f", "-s"]);
+        assert_eq!(
+            command.get_envs().collect::<Vec<_>>(),
+            vec![(
+                OsStr::new("FONTCONFIG_PATH"),
+                Some(OsStr::new("/tmp/wkhtmltopdf-fontconfig"))
+            )]
+        );
+    }
+}
@@ -0,0 +1,46 @@
+use anyhow::anyhow;
+use std::path::Path;
+use std::process::{Command, Stdio};
+use std::string::ToString;
+
+#[allow(unused_imports)]
+use crate::{debug, error, info, warn};
+
+/// Writes a PDF/A-2b copy of `input` to `output`. Ghostscript carries on past most problems, e.g.
+/// fonts it can't embed, so its warnings are returned rather than failing the conversion.
+pub fn convert_to_pdf_a(gs_path: &str, input: &Path, output: &Path) -> anyhow::Result<Vec<String>> {
+    info!("Converting PDF to PDF/A-2b using Ghostscript");
+    let result = Command::new(gs_path)
+        .stdin(Stdio::null())
+        .args([
+            "-q",
+            "-dPDFA=2",
+            "-dPDFACompatibilityPolicy=1",
+            "-dBATCH",
+            "-dNOPAUSE",
+            "-dNOOUTERSAVE",
+            "-dSAFER",
+            "-sColorConversionStrategy=RGB",
+            "-sDEVICE=pdfwrite",
+        ])
+        .arg(format!("-sOutputFile={}", output.to_string_lossy()))
+        .arg(input)
+        .output()
+        .map_err(|e| anyhow!("Failed to run Ghostscript: {}", e.to_string()))?;
+    let stdout = String::from_utf8_lossy(&result.stdout);
+    let stderr = String::from_utf8_lossy(&result.stderr);
+    if !result.status.success() {
+        return Err(anyhow!(
+            "Ghostscript exited with {}: {}",
+            result.status,
+            stderr.trim()
+        ));
+    }
+    Ok(stdout
+        .lines()
+        .chain(stderr.lines())
+        .map(|line| line.trim().trim_matches('*').trim())
+        .filter(|line| !line.is_empty())
+        .map(ToString::to_string)
+        .collect())
+}
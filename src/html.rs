@@ -0,0 +1,180 @@
+use ammonia::UrlRelative;
+use pulldown_cmark::{html, Options, Parser};
+use std::collections::HashSet;
+
+/// Renders Markdown into a standalone HTML document, styled with the given CSS if any.
+pub fn markdown_to_html(markdown: &str, css: Option<&str>) -> String {
+    let parser = Parser::new_ext(markdown, Options::all());
+    let mut body = String::new();
+    html::push_html(&mut body, parser);
+
+    let style = match css {
+        Some(css) => format!("<style>\n{}\n</style>\n", css),
+        None => String::new(),
+    };
+    format!(
+        "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n{}</head>\n<body>\n{}</body>\n</html>\n",
+        style, body
+    )
+}
+
+/// Strips scripts, event handlers and other markup outside of ammonia's allowlist, as well as
+/// URLs with schemes other than `data` and `allowed_schemes`. `<style>` elements and `style`
+/// attributes are only kept if they can't load anything but such URLs. Relative URLs, classes and
+/// images are kept. The result is a standalone HTML document again.
+pub fn sanitize(html: &str, allowed_schemes: &[String]) -> String {
+    let mut url_schemes = HashSet::new();
+    url_schemes.insert("data");
+    url_schemes.extend(allowed_schemes.iter().map(String::as_str));
+    let css_schemes = allowed_schemes.to_vec();
+    let body = ammonia::Builder::default()
+        .add_generic_attributes(&["style", "class", "id"])
+        .add_tags(&["style"])
+        .rm_clean_content_tags(&["style"])
+        .attribute_filter(move |_, attribute, value| {
+            // attribute values aren't escaped, so `<style` in the output is always an element
+            if (attribute == "style" && !is_safe_css(value, &css_schemes))
+                || value.to_ascii_lowercase().contains("<style")
+            {
+                None
+            } else {
+                Some(value.into())
+            }
+        })
+        .url_schemes(url_schemes)
+        .url_relative(UrlRelative::PassThrough)
+        .clean(html)
+        .to_string();
+    let body = filter_style_elements(&body, allowed_schemes);
+    format!(
+        "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n</head>\n<body>\n{}\n</body>\n</html>\n",
+        body
+    )
+}
+
+/// Drops `<style>` elements of sanitised HTML whose CSS isn't safe. ammonia can't filter text,
+/// but its output is well-formed, i.e. each element's CSS ends at the first `</style>`.
+fn filter_style_elements(html: &str, allowed_schemes: &[String]) -> String {
+    const CLOSE: &str = "</style>";
+    let mut filtered = String::new();
+    let mut rest = html;
+    while let Some(start) = rest.find("<style") {
+        let open_end = match rest[start..].find('>') {
+            Some(end) => start + end + 1,
+            None => break,
+        };
+        let close = match rest[open_end..].find(CLOSE) {
+            Some(close) => open_end + close,
+            None => break,
+        };
+        if is_safe_css(&rest[open_end..close], allowed_schemes) {
+            filtered.push_str(&rest[..close + CLOSE.len()]);
+        } else {
+            filtered.push_str(&rest[..start]);
+        }
+        rest = &rest[close + CLOSE.len()..];
+    }
+    filtered.push_str(rest);
+    filtered
+}
+
+/// Whether CSS only refers to relative URLs, `data:` URIs and `allowed_schemes`. Escapes,
+/// `@import` and `image-set` are rejected outright rather than parsed.
+fn is_safe_css(css: &str, allowed_schemes: &[String]) -> bool {
+    let css = css.to_ascii_lowercase();
+    if css.contains('\\')
+        || css.contains("@import")
+        || css.contains("image-set")
+        || css.contains("expression(")
+    {
+        return false;
+    }
+    css.split("url(").skip(1).all(|rest| {
+        let target = rest
+            .split(')')
+            .next()
+            .unwrap_or_default()
+            .trim()
+            .trim_matches(['"', '\''])
+            .trim();
+        is_allowed_url(target, allowed_schemes)
+    })
+}
+
+fn is_allowed_url(url: &str, allowed_schemes: &[String]) -> bool {
+    if url.starts_with("//") {
+        return false;
+    }
+    let scheme = url
+        .split([':', '/', '?', '#'])
+        .next()
+        .filter(|scheme| url[scheme.len()..].starts_with(':'));
+    match scheme {
+        Some(scheme) => {
+            scheme == "data"
+                || allowed_schemes
+                    .iter()
+                    .any(|allowed| allowed.eq_ignore_ascii_case(scheme))
+        }
+        None => true,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sanitize_filters_style_elements() {
+        let html = sanitize(
+            "<html><head><style>p > a { color: red }</style></head><body><style>body { background: url(http://169.254.169.254/) }</style><style>@import 'x.css';</style><p>Hi</p>",
+            &[],
+        );
+        assert!(html.contains("<style>p > a { color: red }</style>"));
+        assert!(!html.contains("169.254.169.254"));
+        assert!(!html.contains("@import"));
+        assert!(html.contains("<p>Hi</p>"));
+    }
+
+    #[test]
+    fn sanitize_keeps_style_lookalikes_out_of_attributes() {
+        let html = sanitize(
+            r#"<p title="<style>">a</p><style>@import 'x.css';</style><p>b</p>"#,
+            &[],
+        );
+        assert!(html.contains("<p>a</p>"));
+        assert!(!html.contains("@import"));
+        assert!(html.contains("<p>b</p>"));
+    }
+
+    #[test]
+    fn sanitize_filters_style_attributes() {
+        let html = sanitize(
+            r#"<p style="color: red">a</p><p style="background: url(http://internal/)">b</p><p style="background: u\72l(http://internal/)">c</p>"#,
+            &[],
+        );
+        assert!(html.contains(r#"<p style="color: red">a</p>"#));
+        assert!(html.contains("<p>b</p>"));
+        assert!(html.contains("<p>c</p>"));
+    }
+
+    #[test]
+    fn css_urls_follow_allowed_schemes() {
+        let https = vec!["https".to_owned()];
+        assert!(is_safe_css("background: url('img/a.png')", &[]));
+        assert!(is_safe_css(
+            "background: url(data:image/png;base64,AA)",
+            &[]
+        ));
+        assert!(!is_safe_css(
+            "background: url(https://example.com/a.png)",
+            &[]
+        ));
+        assert!(is_safe_css(
+            "background: URL(https://example.com/a.png)",
+            &https
+        ));
+        assert!(!is_safe_css("background: url(//example.com/a.png)", &https));
+        assert!(!is_safe_css("@import 'a.css'", &https));
+    }
+}
@@ -0,0 +1,104 @@
+use anyhow::anyhow;
+use once_cell::sync::OnceCell;
+use std::env;
+use std::fs;
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+use std::process::{Command, Stdio};
+
+#[allow(unused_imports)]
+use crate::{debug, error, info, warn};
+
+const WKHTMLTOPDF_LAYER_PATH: &str = "/opt/bin/wkhtmltopdf";
+const WKHTMLTOPDF_BUNDLED_PATH: &str = "/bin/wkhtmltopdf";
+
+static WKHTMLTOPDF_VERSION: OnceCell<Option<String>> = OnceCell::new();
+
+/// Where wkhtmltopdf and its fonts live, a layer, bundled with the function or the system.
+pub struct Installation {
+    pub wkhtmltopdf_path: String,
+    pub fontconfig_path: String,
+}
+
+impl Installation {
+    /// Picks the first executable wkhtmltopdf of the layer, the bundle and the system.
+    pub fn resolve() -> anyhow::Result<Self> {
+        let mut candidates = vec![(WKHTMLTOPDF_LAYER_PATH.to_owned(), "/opt/fonts".to_owned())];
+        if let Ok(task_root) = env::var("LAMBDA_TASK_ROOT") {
+            candidates.push((
+                task_root.clone() + WKHTMLTOPDF_BUNDLED_PATH,
+                task_root + "/fonts",
+            ));
+        }
+        candidates.push((
+            "/usr/bin/wkhtmltopdf".to_owned(),
+            "/usr/share/fonts".to_owned(),
+        ));
+
+        for (wkhtmltopdf_path, fontconfig_path) in &candidates {
+            if is_executable(Path::new(wkhtmltopdf_path)) {
+                return Ok(Installation {
+                    wkhtmltopdf_path: wkhtmltopdf_path.clone(),
+                    fontconfig_path: fontconfig_path.clone(),
+                });
+            }
+        }
+
+        Err(anyhow!(
+            "No executable wkhtmltopdf found, tried {}",
+            candidates
+                .iter()
+                .map(|(wkhtmltopdf_path, fontconfig_path)| format!(
+                    "{} (fontconfig path {})",
+                    wkhtmltopdf_path, fontconfig_path
+                ))
+                .collect::<Vec<_>>()
+                .join(", ")
+        ))
+    }
+
+    /// An executable shipped next to wkhtmltopdf, e.g. qpdf in the same layer.
+    pub fn sibling(&self, name: &str) -> Option<String> {
+        let path = Path::new(&self.wkhtmltopdf_path).with_file_name(name);
+        if is_executable(&path) {
+            Some(path.to_string_lossy().to_string())
+        } else {
+            None
+        }
+    }
+
+    /// Probes `wkhtmltopdf --version` once per container. `None` if the probe fails.
+    pub fn version(&self) -> Option<String> {
+        WKHTMLTOPDF_VERSION
+            .get_or_init(|| {
+                let output = Command::new(&self.wkhtmltopdf_path)
+                    .arg("--version")
+                    .env("FONTCONFIG_PATH", &self.fontconfig_path)
+                    .stdin(Stdio::null())
+                    .output();
+                match output {
+                    Ok(output) if output.status.success() => {
+                        let version = String::from_utf8_lossy(&output.stdout).trim().to_owned();
+                        info!("wkhtmltopdf version: {}", version);
+                        Some(version)
+                    }
+                    Ok(output) => {
+                        warn!("wkhtmltopdf --version exited with {}", output.status);
+                        None
+                    }
+                    Err(e) => {
+                        warn!("Failed to run wkhtmltopdf --version: {}", e);
+                        None
+                    }
+                }
+            })
+            .clone()
+    }
+}
+
+fn is_executable(path: &Path) -> bool {
+    match fs::metadata(path) {
+        Ok(metadata) => metadata.is_file() && metadata.permissions().mode() & 0o111 != 0,
+        Err(_) => false,
+    }
+}
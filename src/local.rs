@@ -0,0 +1,122 @@
+use anyhow::anyhow;
+use std::env;
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::string::ToString;
+use tempfile::Builder;
+
+/// Resolves the absolute destination of a local output, checking that its directory exists and
+/// is writable before anything is rendered. Outputs have to end up under `LOCAL_OUTPUT_ROOT`, so
+/// that requests can't overwrite files of the function itself, e.g. its fontconfig.
+pub fn resolve(path: &str) -> anyhow::Result<PathBuf> {
+    let root = env::var("LOCAL_OUTPUT_ROOT")
+        .map_err(|_| anyhow!("localOutput requires LOCAL_OUTPUT_ROOT to be set"))?;
+    resolve_in(Path::new(&root), path)
+}
+
+/// Like `resolve`, with relative paths taken relative to `root`.
+fn resolve_in(root: &Path, path: &str) -> anyhow::Result<PathBuf> {
+    let root = fs::canonicalize(root).map_err(|e| {
+        anyhow!(
+            "Local output root {} does not exist: {}",
+            root.display(),
+            e.to_string()
+        )
+    })?;
+    let path = root.join(path);
+    let file_name = path
+        .file_name()
+        .ok_or_else(|| anyhow!("Local output path {} has no file name", path.display()))?;
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    let dir = fs::canonicalize(dir).map_err(|e| {
+        anyhow!(
+            "Local output directory {} does not exist: {}",
+            dir.display(),
+            e.to_string()
+        )
+    })?;
+    if !dir.is_dir() {
+        return Err(anyhow!("{} is not a directory", dir.display()));
+    }
+    if !dir.starts_with(&root) {
+        return Err(anyhow!(
+            "Local output path {} is outside of {}",
+            path.display(),
+            root.display()
+        ));
+    }
+    Builder::new()
+        .prefix(".wkhtmltopdf-probe")
+        .tempfile_in(&dir)
+        .map_err(|e| {
+            anyhow!(
+                "Local output directory {} is not writable: {}",
+                dir.display(),
+                e.to_string()
+            )
+        })?;
+    Ok(dir.join(file_name))
+}
+
+/// Writes the PDF next to its destination first and renames it into place, so readers on a
+/// shared filesystem never see a partial file.
+pub fn write(path: &Path, contents: &[u8]) -> anyhow::Result<()> {
+    let dir = path.parent().unwrap_or_else(|| Path::new("."));
+    let mut file = Builder::new()
+        .prefix(".wkhtmltopdf-output")
+        .tempfile_in(dir)
+        .map_err(|e| anyhow!("Failed to create {}: {}", path.display(), e.to_string()))?;
+    file.write_all(contents)
+        .map_err(|e| anyhow!("Failed to write {}: {}", path.display(), e.to_string()))?;
+    file.persist(path)
+        .map_err(|e| anyhow!("Failed to write {}: {}", path.display(), e.to_string()))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    #[test]
+    fn outputs_stay_under_the_root() {
+        let root = tempdir().unwrap();
+        let reports = root.path().join("reports");
+        fs::create_dir(&reports).unwrap();
+        let reports = fs::canonicalize(reports).unwrap();
+
+        assert_eq!(
+            resolve_in(root.path(), "reports/a.pdf").unwrap(),
+            reports.join("a.pdf")
+        );
+        let absolute = reports.join("b.pdf");
+        assert_eq!(
+            resolve_in(root.path(), absolute.to_str().unwrap()).unwrap(),
+            absolute
+        );
+        for path in &[
+            "../a.pdf",
+            "reports/../../a.pdf",
+            "/tmp/a.pdf",
+            "reports/..",
+        ] {
+            assert!(
+                resolve_in(root.path(), path).is_err(),
+                "{} was accepted",
+                path
+            );
+        }
+    }
+
+    #[test]
+    fn symlinks_out_of_the_root_are_rejected() {
+        let root = tempdir().unwrap();
+        let outside = tempdir().unwrap();
+        std::os::unix::fs::symlink(outside.path(), root.path().join("link")).unwrap();
+        assert!(resolve_in(root.path(), "link/a.pdf").is_err());
+    }
+}
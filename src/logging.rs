@@ -0,0 +1,36 @@
+use chrono::{SecondsFormat, Utc};
+use once_cell::sync::Lazy;
+use serde_json::json;
+use slog::{Drain, OwnedKVList, Record};
+use std::io::{self, Write};
+use std::sync::Mutex;
+
+/// ID of the invocation being handled, a container only handles one at a time.
+static REQUEST_ID: Lazy<Mutex<Option<String>>> = Lazy::new(|| Mutex::new(None));
+
+pub fn set_request_id(request_id: &str) {
+    if let Ok(mut current) = REQUEST_ID.lock() {
+        *current = Some(request_id.to_owned());
+    }
+}
+
+/// Writes each record as a JSON object on its own line, for `LOG_FORMAT=json`.
+pub struct JsonDrain;
+
+impl Drain for JsonDrain {
+    type Ok = ();
+    type Err = io::Error;
+
+    fn log(&self, record: &Record, _: &OwnedKVList) -> io::Result<()> {
+        let request_id = REQUEST_ID.lock().ok().and_then(|id| id.clone());
+        let line = json!({
+            "level": record.level().as_str().to_lowercase(),
+            "message": record.msg().to_string(),
+            "timestamp": Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true),
+            "request_id": request_id,
+        });
+        let stderr = io::stderr();
+        let mut stderr = stderr.lock();
+        writeln!(stderr, "{}", line)
+    }
+}
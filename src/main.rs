@@ -1,25 +1,270 @@
+mod apigateway;
+mod callback;
+mod config;
+mod error;
+mod events;
+mod fonts;
+mod ghostscript;
+mod html;
+mod installation;
+mod local;
+mod logging;
+mod metrics;
+mod options;
+mod pdf;
+mod qpdf;
+mod renders;
+mod s3;
+mod shutdown;
+mod sns;
+mod stderr;
+mod urls;
 mod utils;
 mod wkhtmltopdf;
+mod xray;
 
 use lambda_runtime::error::HandlerError;
 use lambda_runtime::lambda;
 use once_cell::sync::OnceCell;
-use serde::{Deserialize, Serialize};
+use serde::de::Error as _;
+use serde::{Deserialize, Deserializer, Serialize};
 use slog::{Drain, Logger};
 use std::cmp::PartialEq;
 use std::error::Error;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use tokio::runtime::Runtime;
 
 #[allow(unused_imports)]
 use utils::*;
 
 static LOGGER: OnceCell<Logger> = OnceCell::new();
+static RUNTIME: OnceCell<Mutex<Runtime>> = OnceCell::new();
+static TEMP_DIR: OnceCell<PathBuf> = OnceCell::new();
+
+/// Several independent documents rendered in one invocation, each one as if it was sent on its
+/// own.
+#[derive(Deserialize)]
+pub struct BatchRequest {
+    documents: Vec<serde_json::Value>,
+}
+
+#[derive(Serialize)]
+pub struct BatchResponse {
+    results: Vec<PdfResponse>,
+}
+
+/// Reply to `{"warmup": true}`, which checks the installation without rendering anything.
+#[derive(Default, Serialize)]
+pub struct HealthResponse {
+    success: bool,
+    messages: Vec<String>,
+    #[serde(rename = "wkhtmltopdfVersion", skip_serializing_if = "Option::is_none")]
+    wkhtmltopdf_version: Option<String>,
+    #[serde(rename = "wkhtmltopdfPath", skip_serializing_if = "Option::is_none")]
+    wkhtmltopdf_path: Option<String>,
+    #[serde(rename = "fontconfigPath", skip_serializing_if = "Option::is_none")]
+    fontconfig_path: Option<String>,
+}
 
 #[derive(Deserialize, Clone)]
 pub struct PdfRequest {
     #[serde(default = "Vec::new")]
     options: Vec<PdfOption>,
+    #[serde(rename = "defaultPageOptions", default = "Vec::new")]
+    default_page_options: Vec<PdfOption>,
+    #[serde(default = "Vec::new")]
+    pages: Vec<PdfPage>,
+    #[serde(default = "Vec::new")]
+    groups: Vec<PageGroup>,
+    /// Every destination gets the same render, the first one is reported at the top level of the
+    /// response.
+    #[serde(rename = "output", default, deserialize_with = "one_or_many")]
+    outputs: Vec<S3Details>,
+    #[serde(rename = "localOutput")]
+    local_output: Option<LocalOutput>,
+    metadata: Option<Metadata>,
+    encryption: Option<Encryption>,
+    watermark: Option<Watermark>,
+    #[serde(rename = "timeoutSeconds")]
+    timeout_seconds: Option<u64>,
+    #[serde(rename = "allowPrivateUrls", default)]
+    allow_private_urls: bool,
+    #[serde(rename = "dryRun", default)]
+    dry_run: bool,
+    #[serde(rename = "validateOnly", default)]
+    validate_only: bool,
+    #[serde(rename = "markdownCssBase64")]
+    markdown_css_base64: Option<String>,
+    #[serde(rename = "injectCssBase64")]
+    inject_css_base64: Option<String>,
+    #[serde(default)]
+    grayscale: bool,
+    #[serde(rename = "lowQuality", default)]
+    low_quality: bool,
+    #[serde(rename = "noPdfCompression", default)]
+    no_pdf_compression: bool,
+    #[serde(rename = "ignoreLoadErrors", default)]
+    ignore_load_errors: bool,
+    #[serde(rename = "pageErrorPolicy")]
+    page_error_policy: Option<PageErrorPolicy>,
+    #[serde(rename = "usePrintMediaType")]
+    use_print_media_type: Option<bool>,
+    #[serde(rename = "compressResponse", default)]
+    compress_response: bool,
+    #[serde(rename = "outputFormat", default)]
+    output_format: OutputFormat,
+    #[serde(rename = "outputMode")]
+    output_mode: Option<OutputMode>,
+    #[serde(rename = "pdfA", default)]
+    pdf_a: bool,
+    #[serde(rename = "pageRange")]
+    page_range: Option<String>,
+    title: Option<String>,
+    zoom: Option<f64>,
+    dpi: Option<u32>,
+    #[serde(rename = "pageSize")]
+    page_size: Option<String>,
+    orientation: Option<String>,
+    margins: Option<Margins>,
+    header: Option<HeaderFooter>,
+    footer: Option<HeaderFooter>,
+    #[serde(rename = "scopedFileAccess", default)]
+    scoped_file_access: bool,
+    proxy: Option<String>,
+    #[serde(rename = "proxyHostnameLookup", default)]
+    proxy_hostname_lookup: bool,
+    #[serde(rename = "userAgent")]
+    user_agent: Option<String>,
+    #[serde(rename = "sanitizeHtml", default)]
+    sanitize_html: bool,
+    #[serde(rename = "sanitizeAllowedSchemes", default = "Vec::new")]
+    sanitize_allowed_schemes: Vec<String>,
+    #[serde(rename = "callbackUrl")]
+    callback_url: Option<String>,
+}
+
+/// Lengths with an optional unit, e.g. `10mm` or `0.5in`.
+#[derive(Deserialize, Clone, Default)]
+pub struct Margins {
+    top: Option<String>,
+    bottom: Option<String>,
+    left: Option<String>,
+    right: Option<String>,
+}
+
+/// The text header or footer of every page. The text may contain wkhtmltopdf's variables such as
+/// `[page]` and `[topage]`.
+#[derive(Deserialize, Clone)]
+pub struct HeaderFooter {
+    left: Option<String>,
+    center: Option<String>,
+    right: Option<String>,
+    /// Between the header or footer and the content, in mm
+    spacing: Option<f64>,
+    #[serde(rename = "fontSize")]
+    font_size: Option<u32>,
+}
+
+/// What wkhtmltopdf does when a page or its media fail to load.
+#[derive(Deserialize, PartialEq, Clone, Copy)]
+pub enum PageErrorPolicy {
+    #[serde(rename = "strict")]
+    Strict,
+    #[serde(rename = "skip")]
+    Skip,
+    #[serde(rename = "ignore")]
+    Ignore,
+}
+
+impl PdfRequest {
+    /// The primary S3 destination.
+    pub fn output(&self) -> Option<&S3Details> {
+        self.outputs.first()
+    }
+}
+
+/// `output` is either a single destination or a list of them.
+fn one_or_many<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<S3Details>, D::Error> {
+    match serde_json::Value::deserialize(deserializer)? {
+        serde_json::Value::Null => Ok(Vec::new()),
+        serde_json::Value::Array(outputs) if outputs.is_empty() => Err(D::Error::custom(
+            "output must list at least one destination",
+        )),
+        serde_json::Value::Array(outputs) => outputs
+            .into_iter()
+            .map(serde_json::from_value)
+            .collect::<Result<_, _>>()
+            .map_err(D::Error::custom),
+        output => serde_json::from_value(output)
+            .map(|output| vec![output])
+            .map_err(D::Error::custom),
+    }
+}
+
+impl PageErrorPolicy {
+    /// The value of `--load-error-handling` and `--load-media-error-handling`.
+    pub fn handling(self) -> &'static str {
+        match self {
+            PageErrorPolicy::Strict => "abort",
+            PageErrorPolicy::Skip => "skip",
+            PageErrorPolicy::Ignore => "ignore",
+        }
+    }
+}
+
+/// Where the output goes. Without an explicit mode, requests with `output` are uploaded and the
+/// others returned inline.
+#[derive(Deserialize, PartialEq, Clone, Copy)]
+pub enum OutputMode {
+    #[serde(rename = "upload")]
+    Upload,
+    #[serde(rename = "inline")]
+    Inline,
+    #[serde(rename = "uploadAndInline")]
+    UploadAndInline,
+}
+
+/// PDFs are rendered by wkhtmltopdf, images of a single page by wkhtmltoimage.
+#[derive(Deserialize, PartialEq, Clone, Copy, Default)]
+pub enum OutputFormat {
+    #[default]
+    #[serde(rename = "pdf")]
+    Pdf,
+    #[serde(rename = "png")]
+    Png,
+    #[serde(rename = "jpeg")]
+    Jpeg,
+}
+
+impl OutputFormat {
+    pub fn is_image(self) -> bool {
+        self != OutputFormat::Pdf
+    }
+
+    /// Also what wkhtmltoimage expects in `--format`.
+    pub fn extension(self) -> &'static str {
+        match self {
+            OutputFormat::Pdf => "pdf",
+            OutputFormat::Png => "png",
+            OutputFormat::Jpeg => "jpg",
+        }
+    }
+
+    pub fn content_type(self) -> &'static str {
+        match self {
+            OutputFormat::Pdf => "application/pdf",
+            OutputFormat::Png => "image/png",
+            OutputFormat::Jpeg => "image/jpeg",
+        }
+    }
+}
+
+/// Pages rendered to a PDF of their own, which is then merged with the other groups'.
+#[derive(Deserialize, Clone)]
+pub struct PageGroup {
+    name: Option<String>,
     pages: Vec<PdfPage>,
-    output: S3Details,
 }
 
 #[derive(Deserialize, Clone)]
@@ -28,10 +273,56 @@ pub struct PdfPage {
     page_type: PageType,
     #[serde(rename = "htmlBase64")]
     html_base64: Option<String>,
+    #[serde(rename = "htmlEncoding", default)]
+    html_encoding: HtmlEncoding,
     #[serde(rename = "htmlUrl")]
     html_url: Option<String>,
+    #[serde(rename = "htmlS3")]
+    html_s3: Option<S3Object>,
+    #[serde(rename = "markdownBase64")]
+    markdown_base64: Option<String>,
+    #[serde(rename = "assetsZipBase64")]
+    assets_zip_base64: Option<String>,
+    #[serde(rename = "xslBase64")]
+    xsl_base64: Option<String>,
+    #[serde(rename = "headerHtmlBase64")]
+    header_html_base64: Option<String>,
+    #[serde(rename = "footerHtmlBase64")]
+    footer_html_base64: Option<String>,
+    #[serde(rename = "injectCssBase64")]
+    inject_css_base64: Option<String>,
     #[serde(default = "Vec::new")]
     options: Vec<PdfOption>,
+    #[serde(rename = "customHeaders", default = "Vec::new")]
+    custom_headers: Vec<(String, String)>,
+    #[serde(default = "Vec::new")]
+    cookies: Vec<(String, String)>,
+    #[serde(rename = "inlineImages", default = "Vec::new")]
+    inline_images: Vec<InlineImage>,
+    #[serde(rename = "javascriptDelayMs")]
+    javascript_delay_ms: Option<u64>,
+    #[serde(rename = "disableJavascript", default)]
+    disable_javascript: bool,
+    #[serde(rename = "usePrintMediaType")]
+    use_print_media_type: Option<bool>,
+}
+
+/// An image embedded into the page HTML as a `data:` URI wherever `src` refers to `name`.
+#[derive(Deserialize, Clone)]
+pub struct InlineImage {
+    name: String,
+    mime: String,
+    base64: String,
+}
+
+/// How `htmlBase64` is encoded before Base64.
+#[derive(Deserialize, PartialEq, Clone, Copy, Default)]
+pub enum HtmlEncoding {
+    #[default]
+    #[serde(rename = "plain")]
+    Plain,
+    #[serde(rename = "gzip")]
+    Gzip,
 }
 
 #[derive(Deserialize, strum_macros::Display, PartialEq, Clone)]
@@ -50,9 +341,110 @@ pub struct PdfOption {
     value: Option<String>,
 }
 
+#[derive(Deserialize, Clone)]
+pub struct Metadata {
+    title: Option<String>,
+    author: Option<String>,
+    subject: Option<String>,
+    keywords: Option<String>,
+}
+
+/// Stamped onto every page, either `text` or the first page of `pdfBase64`.
+#[derive(Deserialize, Clone)]
+pub struct Watermark {
+    text: Option<String>,
+    #[serde(rename = "pdfBase64")]
+    pdf_base64: Option<String>,
+    opacity: Option<f64>,
+    rotation: Option<f64>,
+    #[serde(rename = "fontSize")]
+    font_size: Option<f64>,
+}
+
+#[derive(Deserialize, Clone)]
+pub struct Encryption {
+    #[serde(rename = "userPassword")]
+    user_password: Option<String>,
+    #[serde(rename = "ownerPassword")]
+    owner_password: String,
+    #[serde(rename = "bitLength")]
+    bit_length: Option<u32>,
+}
+
+#[derive(Deserialize, Clone)]
+pub struct LocalOutput {
+    path: String,
+}
+
 #[derive(Deserialize, Clone)]
 pub struct S3Details {
     region: Option<String>,
+    endpoint: Option<String>,
+    bucket: String,
+    #[serde(rename = "objectKey")]
+    object_key: String,
+    sse: Option<String>,
+    #[serde(rename = "kmsKeyId")]
+    kms_key_id: Option<String>,
+    #[serde(rename = "downloadFilename")]
+    download_filename: Option<String>,
+    #[serde(rename = "streamUpload", default)]
+    stream_upload: bool,
+    #[serde(rename = "skipIfExists", default)]
+    skip_if_exists: bool,
+    #[serde(rename = "failIfExists", default)]
+    fail_if_exists: bool,
+    #[serde(rename = "useDualstack", default)]
+    use_dualstack: bool,
+    #[serde(rename = "useAccelerate", default)]
+    use_accelerate: bool,
+    #[serde(default = "Vec::new")]
+    tags: Vec<(String, String)>,
+    #[serde(rename = "presignSeconds")]
+    presign_seconds: Option<u64>,
+    #[serde(rename = "cacheControl")]
+    cache_control: Option<String>,
+    expires: Option<String>,
+    /// Overrides the output format's content type
+    #[serde(rename = "contentType")]
+    content_type: Option<String>,
+    #[serde(rename = "storageClass")]
+    storage_class: Option<String>,
+    #[serde(rename = "assumeRoleArn")]
+    assume_role_arn: Option<String>,
+    #[serde(rename = "externalId")]
+    external_id: Option<String>,
+    credentials: Option<Credentials>,
+}
+
+/// Static credentials for S3-compatible stores, e.g. LocalStack in tests. They're never logged.
+#[derive(Deserialize, Clone)]
+pub struct Credentials {
+    #[serde(rename = "accessKeyId")]
+    access_key_id: String,
+    #[serde(rename = "secretAccessKey")]
+    secret_access_key: String,
+    #[serde(rename = "sessionToken")]
+    session_token: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, strum_macros::Display, PartialEq, Clone, Copy, Debug)]
+#[serde(rename_all = "snake_case")]
+#[strum(serialize_all = "snake_case")]
+pub enum ErrorCode {
+    InvalidRequest,
+    RenderFailed,
+    UploadFailed,
+    Timeout,
+    /// Too many renders running in the container, worth retrying
+    TooBusy,
+    Internal,
+}
+
+#[derive(Deserialize, Clone)]
+pub struct S3Object {
+    region: Option<String>,
+    endpoint: Option<String>,
     bucket: String,
     #[serde(rename = "objectKey")]
     object_key: String,
@@ -62,19 +454,119 @@ pub struct S3Details {
 pub struct PdfResponse {
     success: bool,
     messages: Vec<String>,
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    skipped: bool,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    warnings: Vec<String>,
+    #[serde(rename = "errorCode", skip_serializing_if = "Option::is_none")]
+    error_code: Option<ErrorCode>,
+    #[serde(rename = "objectKey", skip_serializing_if = "Option::is_none")]
+    object_key: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    etag: Option<String>,
+    #[serde(rename = "versionId", skip_serializing_if = "Option::is_none")]
+    version_id: Option<String>,
+    #[serde(rename = "downloadUrl", skip_serializing_if = "Option::is_none")]
+    download_url: Option<String>,
+    #[serde(rename = "outputPath", skip_serializing_if = "Option::is_none")]
+    output_path: Option<String>,
+    #[serde(rename = "pdfBase64", skip_serializing_if = "Option::is_none")]
+    pdf_base64: Option<String>,
+    #[serde(rename = "contentEncoding", skip_serializing_if = "Option::is_none")]
+    content_encoding: Option<String>,
+    #[serde(rename = "outputBytes", skip_serializing_if = "Option::is_none")]
+    output_bytes: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    sha256: Option<String>,
+    #[serde(rename = "contentMd5", skip_serializing_if = "Option::is_none")]
+    content_md5: Option<String>,
+    #[serde(rename = "pageCount", skip_serializing_if = "Option::is_none")]
+    page_count: Option<u32>,
+    #[serde(rename = "wkhtmltopdfVersion", skip_serializing_if = "Option::is_none")]
+    wkhtmltopdf_version: Option<String>,
+    #[serde(rename = "commandPreview", skip_serializing_if = "Option::is_none")]
+    command_preview: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    progress: Option<Progress>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pages: Vec<PageSummary>,
+    #[serde(rename = "renderMs", skip_serializing_if = "Option::is_none")]
+    render_ms: Option<u64>,
+    #[serde(rename = "uploadMs", skip_serializing_if = "Option::is_none")]
+    upload_ms: Option<u64>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    destinations: Vec<Destination>,
+}
+
+/// The outcome of one upload when `output` lists several destinations.
+#[derive(Serialize, Clone)]
+pub struct Destination {
+    success: bool,
+    bucket: String,
+    #[serde(rename = "objectKey")]
+    object_key: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    etag: Option<String>,
+    #[serde(rename = "versionId", skip_serializing_if = "Option::is_none")]
+    version_id: Option<String>,
+    #[serde(rename = "downloadUrl", skip_serializing_if = "Option::is_none")]
+    download_url: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    message: Option<String>,
+}
+
+/// What became of an input page, in the order of the request's pages (across all groups).
+#[derive(Serialize, Clone)]
+pub struct PageSummary {
+    index: usize,
+    /// `url`, `base64`, `s3`, `markdown` or `toc`
+    source: &'static str,
+    /// `ok`, `failed` or `unknown` when wkhtmltopdf's errors can't be attributed to pages
+    #[serde(rename = "loadStatus")]
+    load_status: &'static str,
+}
+
+/// How far wkhtmltopdf got before failing.
+#[derive(Serialize, Clone)]
+pub struct Progress {
+    phase: String,
+    step: u32,
+    steps: u32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    percent: Option<u32>,
 }
 
 fn main() -> Result<(), Box<dyn Error>> {
-    let decorator = slog_term::TermDecorator::new().build();
-    let drain = slog_term::FullFormat::new(decorator).build().fuse();
-    let drain = std::sync::Mutex::new(drain).fuse();
-    let logger = Logger::root(drain, slog::o!());
+    let level = log_level()?;
+    let logger = if std::env::var("LOG_FORMAT")
+        .map(|format| format == "json")
+        .unwrap_or(false)
+    {
+        let drain = logging::JsonDrain.fuse();
+        Logger::root(slog::LevelFilter::new(drain, level).fuse(), slog::o!())
+    } else {
+        let decorator = slog_term::TermDecorator::new().build();
+        let drain = slog_term::FullFormat::new(decorator).build().fuse();
+        let drain = std::sync::Mutex::new(drain).fuse();
+        Logger::root(slog::LevelFilter::new(drain, level).fuse(), slog::o!())
+    };
     LOGGER
         .set(logger)
         .map_err(|_| HandlerError::from("Failed to initialise logger"))?;
+    RUNTIME
+        .set(Mutex::new(Runtime::new()?))
+        .map_err(|_| HandlerError::from("Failed to initialise Tokio runtime"))?;
+    let temp_dir = resolve_temp_dir()?;
+    info!("Temp directory: {}", temp_dir.display());
+    TEMP_DIR
+        .set(temp_dir)
+        .map_err(|_| HandlerError::from("Failed to initialise temp directory"))?;
+
+    shutdown::install()?;
+    fonts::warm_cache();
 
     info!("Initialisation completed");
-    lambda!(wkhtmltopdf::convert);
+    lambda!(wkhtmltopdf::handle);
 
     Ok(())
 }
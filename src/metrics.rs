@@ -0,0 +1,84 @@
+use anyhow::anyhow;
+use serde_json::{json, Map, Value};
+use std::env;
+use std::string::ToString;
+use std::time::Duration;
+
+#[allow(unused_imports)]
+use crate::{debug, error, info, warn};
+
+const DEFAULT_NAMESPACE: &str = "wkhtmltopdf-lambda";
+
+#[derive(Default)]
+pub struct Metrics {
+    pub render: Option<Duration>,
+    pub upload: Option<Duration>,
+    pub output_bytes: Option<u64>,
+}
+
+/// Prints the metrics as a CloudWatch Embedded Metric Format line when EMF_METRICS=1. Besides the
+/// success/failure `Outcome`, extra dimensions can be set in EMF_DIMENSIONS as `Name=Value` pairs
+/// separated by commas.
+pub fn emit(metrics: &Metrics, success: bool) {
+    if env::var("EMF_METRICS").map(|v| v == "1").unwrap_or(false) {
+        match format(metrics, success) {
+            Ok(line) => println!("{}", line),
+            Err(e) => {
+                warn!("Failed to emit metrics: {}", e);
+            }
+        }
+    }
+}
+
+fn format(metrics: &Metrics, success: bool) -> anyhow::Result<String> {
+    let namespace = env::var("EMF_NAMESPACE").unwrap_or_else(|_| DEFAULT_NAMESPACE.to_owned());
+    let mut dimensions = vec![(
+        "Outcome".to_owned(),
+        if success { "success" } else { "failure" }.to_owned(),
+    )];
+    if let Ok(extra) = env::var("EMF_DIMENSIONS") {
+        for pair in extra.split(',').map(str::trim).filter(|p| !p.is_empty()) {
+            let mut parts = pair.splitn(2, '=');
+            match (parts.next(), parts.next()) {
+                (Some(name), Some(value)) if !name.trim().is_empty() => {
+                    dimensions.push((name.trim().to_owned(), value.trim().to_owned()))
+                }
+                _ => return Err(anyhow!("Invalid EMF_DIMENSIONS: {}", pair)),
+            }
+        }
+    }
+
+    let mut definitions = Vec::new();
+    let mut root = Map::new();
+    for (name, unit, value) in &[
+        ("RenderMillis", "Milliseconds", metrics.render.map(millis)),
+        ("UploadMillis", "Milliseconds", metrics.upload.map(millis)),
+        ("OutputBytes", "Bytes", metrics.output_bytes),
+    ] {
+        if let Some(value) = value {
+            definitions.push(json!({ "Name": name, "Unit": unit }));
+            root.insert(name.to_string(), json!(value));
+        }
+    }
+    for (name, value) in &dimensions {
+        root.insert(name.clone(), json!(value));
+    }
+    let dimension_names: Vec<&str> = dimensions.iter().map(|(name, _)| name.as_str()).collect();
+    root.insert(
+        "_aws".to_owned(),
+        json!({
+            "Timestamp": chrono::Utc::now().timestamp_millis(),
+            "CloudWatchMetrics": [{
+                "Namespace": namespace,
+                "Dimensions": [dimension_names],
+                "Metrics": definitions,
+            }],
+        }),
+    );
+    serde_json::to_string(&Value::Object(root))
+        .map_err(|e| anyhow!("Failed to serialise metrics: {}", e.to_string()))
+}
+
+pub fn millis(duration: Duration) -> u64 {
+    duration.as_millis() as u64
+}
@@ -0,0 +1,438 @@
+use anyhow::anyhow;
+use std::env;
+
+use crate::PdfOption;
+#[allow(unused_imports)]
+use crate::{debug, error, info, warn};
+
+/// Rendering flags permitted when `OPTION_ALLOWLIST` is set to `default`.
+const DEFAULT_ALLOWED_OPTIONS: &[&str] = &[
+    "--margin-top",
+    "-T",
+    "--margin-bottom",
+    "-B",
+    "--margin-left",
+    "-L",
+    "--margin-right",
+    "-R",
+    "--page-size",
+    "-s",
+    "--page-width",
+    "--page-height",
+    "--orientation",
+    "-O",
+    "--header-spacing",
+    "--footer-spacing",
+    "--zoom",
+    "--dpi",
+    "-d",
+    "--image-dpi",
+    "--image-quality",
+    "--grayscale",
+    "-g",
+    "--lowquality",
+    "-l",
+    "--title",
+    "--outline",
+    "--no-outline",
+    "--outline-depth",
+    "--print-media-type",
+    "--no-print-media-type",
+    "--background",
+    "--no-background",
+    "--javascript-delay",
+    "--disable-javascript",
+    "--enable-javascript",
+    "--disable-smart-shrinking",
+    "--enable-smart-shrinking",
+    "--minimum-font-size",
+    "--encoding",
+    "--header-center",
+    "--header-left",
+    "--header-right",
+    "--header-font-name",
+    "--header-font-size",
+    "--header-line",
+    "--no-header-line",
+    "--footer-center",
+    "--footer-left",
+    "--footer-right",
+    "--footer-font-name",
+    "--footer-font-size",
+    "--footer-line",
+    "--no-footer-line",
+];
+
+/// Options whose values mustn't end up in logs, along with the number of values they take. Only
+/// the last value is masked, so that e.g. the names of headers stay visible.
+const SENSITIVE_OPTIONS: &[(&str, usize)] = &[
+    ("--custom-header", 2),
+    ("--cookie", 2),
+    ("--password", 1),
+    ("--proxy", 1),
+    ("--ssl-key-password", 1),
+];
+
+/// Flags wkhtmltopdf accepts more than once, every other flag is treated as single-valued.
+const REPEATABLE_OPTIONS: &[&str] = &[
+    "--allow",
+    "--bypass-proxy-for",
+    "--cookie",
+    "--custom-header",
+    "--post",
+    "--post-file",
+    "--replace",
+    "--run-script",
+];
+
+/// wkhtmltopdf's global options, which apply to the whole document rather than to a page.
+const GLOBAL_ONLY_OPTIONS: &[&str] = &[
+    "--collate",
+    "--no-collate",
+    "--cookie-jar",
+    "--copies",
+    "--dpi",
+    "-d",
+    "--grayscale",
+    "-g",
+    "--image-dpi",
+    "--image-quality",
+    "--log-level",
+    "--lowquality",
+    "-l",
+    "--margin-top",
+    "-T",
+    "--margin-bottom",
+    "-B",
+    "--margin-left",
+    "-L",
+    "--margin-right",
+    "-R",
+    "--orientation",
+    "-O",
+    "--page-height",
+    "--page-size",
+    "-s",
+    "--page-width",
+    "--no-pdf-compression",
+    "--quiet",
+    "-q",
+    "--title",
+    "--use-xserver",
+    "--outline",
+    "--no-outline",
+    "--outline-depth",
+    "--dump-outline",
+    "--dump-default-toc-xsl",
+];
+
+const NUMERIC_OPTIONS: &[&str] = &[
+    "--dpi",
+    "--image-dpi",
+    "--image-quality",
+    "--javascript-delay",
+    "--minimum-font-size",
+    "--zoom",
+    "--header-font-size",
+    "--footer-font-size",
+    "--header-spacing",
+    "--footer-spacing",
+];
+
+/// Lengths which take an optional unit, e.g. `10mm`.
+const LENGTH_OPTIONS: &[&str] = &[
+    "--margin-top",
+    "--margin-bottom",
+    "--margin-left",
+    "--margin-right",
+    "--page-height",
+    "--page-width",
+];
+
+const LENGTH_UNITS: &[&str] = &["mm", "cm", "m", "in", "pt", "pc", "px", "dd", "cc"];
+
+/// The values of these flags are matched case-insensitively, like wkhtmltopdf does.
+const ENUM_OPTIONS: &[(&str, &[&str])] = &[
+    ("--orientation", &["Portrait", "Landscape"]),
+    (
+        "--page-size",
+        &[
+            "A0",
+            "A1",
+            "A2",
+            "A3",
+            "A4",
+            "A5",
+            "A6",
+            "A7",
+            "A8",
+            "A9",
+            "B0",
+            "B1",
+            "B2",
+            "B3",
+            "B4",
+            "B5",
+            "B6",
+            "B7",
+            "B8",
+            "B9",
+            "B10",
+            "C5E",
+            "Comm10E",
+            "DLE",
+            "Executive",
+            "Folio",
+            "Ledger",
+            "Legal",
+            "Letter",
+            "Tabloid",
+        ],
+    ),
+];
+
+/// Returns the allowed option names, or `None` if every option is allowed.
+///
+/// `OPTION_ALLOWLIST` is either `default` for the compiled-in list, or a comma-separated list of
+/// flags which replaces it.
+pub fn allowlist() -> Option<Vec<String>> {
+    match env::var("OPTION_ALLOWLIST") {
+        Ok(allowlist) if allowlist.trim() == "default" => Some(
+            DEFAULT_ALLOWED_OPTIONS
+                .iter()
+                .map(|option| option.to_string())
+                .collect(),
+        ),
+        Ok(allowlist) => Some(
+            allowlist
+                .split(',')
+                .map(|option| option.trim().to_owned())
+                .filter(|option| !option.is_empty())
+                .collect(),
+        ),
+        Err(_) => None,
+    }
+}
+
+pub fn check_allowed(options: &[PdfOption], allowlist: Option<&[String]>) -> anyhow::Result<()> {
+    if let Some(allowlist) = allowlist {
+        for option in options {
+            if !allowlist.contains(&option.name) {
+                return Err(anyhow!("Option {} is not allowed", option.name));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Checks the values of well-known numeric and enum flags, so that typos are reported before
+/// wkhtmltopdf is spawned. Other flags are passed through as they are.
+pub fn check_values(options: &[PdfOption]) -> anyhow::Result<()> {
+    for option in options {
+        let name = option.name.as_str();
+        let known = NUMERIC_OPTIONS.contains(&name)
+            || LENGTH_OPTIONS.contains(&name)
+            || ENUM_OPTIONS.iter().any(|(flag, _)| *flag == name);
+        if !known {
+            continue;
+        }
+        let value = option
+            .value
+            .as_deref()
+            .ok_or_else(|| anyhow!("Option {} requires a value", name))?
+            .trim();
+        let valid = if NUMERIC_OPTIONS.contains(&name) {
+            value.parse::<f64>().is_ok()
+        } else if LENGTH_OPTIONS.contains(&name) {
+            let number = LENGTH_UNITS
+                .iter()
+                .find(|unit| value.ends_with(*unit))
+                .map(|unit| value[..value.len() - unit.len()].trim_end())
+                .unwrap_or(value);
+            number.parse::<f64>().is_ok()
+        } else {
+            ENUM_OPTIONS
+                .iter()
+                .filter(|(flag, _)| *flag == name)
+                .flat_map(|(_, values)| values.iter())
+                .any(|known| known.eq_ignore_ascii_case(value))
+        };
+        if !valid {
+            return Err(anyhow!("Invalid value {} for option {}", value, name));
+        }
+    }
+    Ok(())
+}
+
+/// Layers a page's options over the request's default page options. A default is dropped if the
+/// page has an option with the same flag, so the page wins regardless of values; the remaining
+/// defaults come first, followed by the page's options in their original order.
+pub fn merge_defaults(defaults: &[PdfOption], options: &[PdfOption]) -> Vec<PdfOption> {
+    defaults
+        .iter()
+        .filter(|default| !options.iter().any(|option| option.name == default.name))
+        .chain(options.iter())
+        .cloned()
+        .collect()
+}
+
+/// Keeps only the last occurrence of each single-valued flag.
+pub fn dedupe(options: &[PdfOption]) -> Vec<PdfOption> {
+    options
+        .iter()
+        .enumerate()
+        .filter(|(i, option)| {
+            let superseded = !is_repeatable(&option.name)
+                && options[i + 1..]
+                    .iter()
+                    .any(|later| later.name == option.name);
+            if superseded {
+                warn!(
+                    "Ignoring {} overridden later in the same options",
+                    option.name
+                );
+            }
+            !superseded
+        })
+        .map(|(_, option)| option.clone())
+        .collect()
+}
+
+/// Drops the global-only flags in `options` which are also set in any of `overrides`, e.g.
+/// request-level options overridden on a page. Page options are kept, since wkhtmltopdf applies
+/// them to every page and a page's own value already wins for that page.
+pub fn without_overridden(options: &[PdfOption], overrides: &[Vec<PdfOption>]) -> Vec<PdfOption> {
+    options
+        .iter()
+        .filter(|option| {
+            let overridden = GLOBAL_ONLY_OPTIONS.contains(&option.name.as_str())
+                && overrides
+                    .iter()
+                    .flatten()
+                    .any(|override_option| override_option.name == option.name);
+            if overridden {
+                warn!(
+                    "Ignoring request-level {} overridden by a page",
+                    option.name
+                );
+            }
+            !overridden
+        })
+        .cloned()
+        .collect()
+}
+
+fn is_repeatable(name: &str) -> bool {
+    REPEATABLE_OPTIONS.contains(&name)
+}
+
+/// Masks the values of sensitive options for logging.
+pub fn redact(args: &[String]) -> Vec<String> {
+    let sensitive = sensitive_indices(args);
+    args.iter()
+        .enumerate()
+        .map(|(i, arg)| {
+            if sensitive.contains(&i) {
+                "***".to_owned()
+            } else {
+                arg.clone()
+            }
+        })
+        .collect()
+}
+
+/// Masks the values of sensitive options wherever they appear in wkhtmltopdf's output.
+pub fn redact_output(output: &str, args: &[String]) -> String {
+    let mut output = output.to_owned();
+    for i in sensitive_indices(args) {
+        if !args[i].is_empty() {
+            output = output.replace(&args[i], "***");
+        }
+    }
+    output
+}
+
+fn sensitive_indices(args: &[String]) -> Vec<usize> {
+    let mut indices = Vec::new();
+    let mut i = 0;
+    while i < args.len() {
+        if let Some((_, values)) = SENSITIVE_OPTIONS.iter().find(|(name, _)| *name == args[i]) {
+            if i + values < args.len() {
+                indices.push(i + values);
+            }
+            i += values;
+        }
+        i += 1;
+    }
+    indices
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn option(name: &str, value: Option<&str>) -> PdfOption {
+        PdfOption {
+            name: name.to_owned(),
+            value: value.map(str::to_owned),
+        }
+    }
+
+    fn names_and_values(options: &[PdfOption]) -> Vec<(&str, Option<&str>)> {
+        options
+            .iter()
+            .map(|option| (option.name.as_str(), option.value.as_deref()))
+            .collect()
+    }
+
+    #[test]
+    fn dedupe_keeps_last_single_valued_and_every_repeatable() {
+        let options = vec![
+            option("--zoom", Some("1.2")),
+            option("--cookie", Some("a")),
+            option("--zoom", Some("0.8")),
+            option("--cookie", Some("b")),
+        ];
+        assert_eq!(
+            names_and_values(&dedupe(&options)),
+            vec![
+                ("--cookie", Some("a")),
+                ("--zoom", Some("0.8")),
+                ("--cookie", Some("b")),
+            ]
+        );
+    }
+
+    #[test]
+    fn without_overridden_keeps_page_options() {
+        let options = vec![option("--zoom", Some("1.2")), option("--title", Some("A"))];
+        let overrides = vec![
+            Vec::new(),
+            vec![option("--zoom", Some("0.8")), option("--title", Some("B"))],
+        ];
+        assert_eq!(
+            names_and_values(&without_overridden(&options, &overrides)),
+            vec![("--zoom", Some("1.2"))]
+        );
+    }
+
+    #[test]
+    fn page_options_override_defaults() {
+        let defaults = vec![
+            option("--zoom", Some("1.2")),
+            option("--margin-top", Some("10mm")),
+        ];
+        let options = vec![
+            option("--zoom", Some("0.8")),
+            option("--no-background", None),
+        ];
+        assert_eq!(
+            names_and_values(&merge_defaults(&defaults, &options)),
+            vec![
+                ("--margin-top", Some("10mm")),
+                ("--zoom", Some("0.8")),
+                ("--no-background", None),
+            ]
+        );
+    }
+}
@@ -0,0 +1,326 @@
+use anyhow::anyhow;
+use lopdf::content::{Content, Operation};
+use lopdf::{dictionary, Dictionary, Document, Object, Stream};
+use sha2::{Digest, Sha256};
+use std::io::{self, Read};
+use std::path::Path;
+use std::string::ToString;
+
+use crate::{Metadata, Watermark};
+
+const DEFAULT_WATERMARK_OPACITY: f64 = 0.2;
+const DEFAULT_WATERMARK_ROTATION: f64 = 45.0;
+const DEFAULT_WATERMARK_FONT_SIZE: f64 = 72.0;
+// A4 in points, qpdf scales the overlay to fit each page anyway
+const OVERLAY_WIDTH: f64 = 595.0;
+const OVERLAY_HEIGHT: f64 = 842.0;
+
+/// Facts about the rendered PDF reported back to the caller.
+pub struct OutputStats {
+    pub bytes: u64,
+    pub sha256: String,
+    /// Base64-encoded, like the Content-MD5 header
+    pub md5: String,
+    /// Only counted when the whole PDF is in memory
+    pub page_count: Option<u32>,
+}
+
+impl OutputStats {
+    pub fn new(contents: &[u8]) -> Self {
+        OutputStats {
+            bytes: contents.len() as u64,
+            sha256: format!("{:x}", Sha256::digest(contents)),
+            md5: base64::encode(md5::compute(contents).0),
+            page_count: count_pages(contents),
+        }
+    }
+}
+
+/// Hashes and counts whatever is read through it, for output which is never held in memory as a
+/// whole.
+pub struct HashingReader<R: Read> {
+    inner: R,
+    hasher: Sha256,
+    md5: md5::Context,
+    bytes: u64,
+}
+
+impl<R: Read> HashingReader<R> {
+    pub fn new(inner: R) -> Self {
+        HashingReader {
+            inner,
+            hasher: Sha256::new(),
+            md5: md5::Context::new(),
+            bytes: 0,
+        }
+    }
+
+    pub fn finish(self) -> OutputStats {
+        OutputStats {
+            bytes: self.bytes,
+            sha256: format!("{:x}", self.hasher.finalize()),
+            md5: base64::encode(self.md5.compute().0),
+            page_count: None,
+        }
+    }
+}
+
+impl<R: Read> Read for HashingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let length = self.inner.read(buf)?;
+        self.hasher.update(&buf[..length]);
+        self.md5.consume(&buf[..length]);
+        self.bytes += length as u64;
+        Ok(length)
+    }
+}
+
+/// Counts `/Type /Page` objects, which is good enough for the uncompressed object tables
+/// wkhtmltopdf writes. `None` if no page objects can be found at all.
+pub fn count_pages(contents: &[u8]) -> Option<u32> {
+    const TYPE: &[u8] = b"/Type";
+    const PAGE: &[u8] = b"/Page";
+
+    let mut count = 0;
+    let mut i = 0;
+    while i + TYPE.len() <= contents.len() {
+        if &contents[i..i + TYPE.len()] != TYPE {
+            i += 1;
+            continue;
+        }
+        i += TYPE.len();
+        while i < contents.len() && contents[i].is_ascii_whitespace() {
+            i += 1;
+        }
+        if contents[i..].starts_with(PAGE) {
+            i += PAGE.len();
+            // skip /Pages, the page tree nodes
+            if i >= contents.len() || !contents[i].is_ascii_alphanumeric() {
+                count += 1;
+            }
+        }
+    }
+
+    if count == 0 {
+        None
+    } else {
+        Some(count)
+    }
+}
+
+/// Sets the document information fields of the PDF at `path` in place.
+pub fn set_metadata(path: &Path, metadata: &Metadata) -> anyhow::Result<()> {
+    let mut document =
+        Document::load(path).map_err(|e| anyhow!("Failed to parse PDF: {}", e.to_string()))?;
+    let info_id = match document.trailer.get(b"Info").and_then(Object::as_reference) {
+        Ok(info_id) => info_id,
+        Err(_) => {
+            let info_id = document.add_object(Dictionary::new());
+            document.trailer.set("Info", info_id);
+            info_id
+        }
+    };
+    let info = document
+        .get_object_mut(info_id)
+        .and_then(Object::as_dict_mut)
+        .map_err(|e| anyhow!("Invalid PDF information dictionary: {}", e.to_string()))?;
+    for (key, value) in &[
+        ("Title", &metadata.title),
+        ("Author", &metadata.author),
+        ("Subject", &metadata.subject),
+        ("Keywords", &metadata.keywords),
+    ] {
+        if let Some(value) = value {
+            info.set(*key, text_string(value));
+        }
+    }
+    document
+        .save(path)
+        .map_err(|e| anyhow!("Failed to write PDF: {}", e.to_string()))?;
+    Ok(())
+}
+
+/// PDF text strings are either PDFDocEncoding, which ASCII is a subset of, or UTF-16BE with a BOM.
+fn text_string(value: &str) -> Object {
+    if value.is_ascii() {
+        Object::string_literal(value)
+    } else {
+        let mut bytes = vec![0xfe, 0xff];
+        for unit in value.encode_utf16() {
+            bytes.extend_from_slice(&unit.to_be_bytes());
+        }
+        Object::string_literal(bytes)
+    }
+}
+
+pub fn check_watermark(watermark: &Watermark) -> anyhow::Result<()> {
+    match (&watermark.text, &watermark.pdf_base64) {
+        (Some(text), None) => {
+            // WinAnsiEncoding matches Latin-1 apart from the C1 control characters
+            if text.trim().is_empty() || text.chars().any(|c| c.is_control() || c as u32 > 0xff) {
+                return Err(anyhow!(
+                    "Watermark text must not be empty and may only contain Latin-1 characters"
+                ));
+            }
+        }
+        (None, Some(_)) => {}
+        _ => return Err(anyhow!("Watermarks need exactly one of text and pdfBase64")),
+    }
+    if let Some(opacity) = watermark.opacity {
+        if !(0.0..=1.0).contains(&opacity) {
+            return Err(anyhow!("Watermark opacity must be between 0 and 1"));
+        }
+    }
+    if let Some(font_size) = watermark.font_size {
+        if !(1.0..=500.0).contains(&font_size) {
+            return Err(anyhow!("Watermark font size must be between 1 and 500"));
+        }
+    }
+    if watermark.rotation.map(f64::is_finite) == Some(false) {
+        return Err(anyhow!("Invalid watermark rotation"));
+    }
+    Ok(())
+}
+
+/// Writes a single page PDF to `path` with the watermark text in the middle, in translucent grey
+/// Helvetica Bold.
+pub fn write_watermark(watermark: &Watermark, path: &Path) -> anyhow::Result<()> {
+    let text = watermark.text.as_deref().unwrap_or_default();
+    let opacity = watermark.opacity.unwrap_or(DEFAULT_WATERMARK_OPACITY);
+    let font_size = watermark.font_size.unwrap_or(DEFAULT_WATERMARK_FONT_SIZE);
+    let (sin, cos) = watermark
+        .rotation
+        .unwrap_or(DEFAULT_WATERMARK_ROTATION)
+        .to_radians()
+        .sin_cos();
+    // there are no font metrics at hand, 0.6em is about the average width of Helvetica Bold
+    let width = text.chars().count() as f64 * font_size * 0.6;
+
+    let mut document = Document::with_version("1.5");
+    let pages_id = document.new_object_id();
+    let font_id = document.add_object(dictionary! {
+        "Type" => "Font",
+        "Subtype" => "Type1",
+        "BaseFont" => "Helvetica-Bold",
+        "Encoding" => "WinAnsiEncoding",
+    });
+    let state_id = document.add_object(dictionary! {
+        "Type" => "ExtGState",
+        "ca" => opacity,
+        "CA" => opacity,
+    });
+    let content = Content {
+        operations: vec![
+            Operation::new("q", vec![]),
+            Operation::new("gs", vec!["GS1".into()]),
+            Operation::new("g", vec![0.5.into()]),
+            Operation::new(
+                "cm",
+                vec![
+                    1.into(),
+                    0.into(),
+                    0.into(),
+                    1.into(),
+                    (OVERLAY_WIDTH / 2.0).into(),
+                    (OVERLAY_HEIGHT / 2.0).into(),
+                ],
+            ),
+            Operation::new(
+                "cm",
+                vec![
+                    cos.into(),
+                    sin.into(),
+                    (-sin).into(),
+                    cos.into(),
+                    0.into(),
+                    0.into(),
+                ],
+            ),
+            Operation::new("BT", vec![]),
+            Operation::new("Tf", vec!["F1".into(), font_size.into()]),
+            Operation::new("Td", vec![(-width / 2.0).into(), (-font_size / 3.0).into()]),
+            Operation::new(
+                "Tj",
+                vec![Object::string_literal(
+                    text.chars().map(|c| c as u8).collect::<Vec<_>>(),
+                )],
+            ),
+            Operation::new("ET", vec![]),
+            Operation::new("Q", vec![]),
+        ],
+    };
+    let content = content
+        .encode()
+        .map_err(|e| anyhow!("Failed to encode watermark: {}", e.to_string()))?;
+    let content_id = document.add_object(Stream::new(dictionary! {}, content));
+    let page_id = document.add_object(dictionary! {
+        "Type" => "Page",
+        "Parent" => pages_id,
+        "Contents" => content_id,
+        "Resources" => dictionary! {
+            "Font" => dictionary! { "F1" => font_id },
+            "ExtGState" => dictionary! { "GS1" => state_id },
+        },
+        "MediaBox" => vec![0.into(), 0.into(), OVERLAY_WIDTH.into(), OVERLAY_HEIGHT.into()],
+    });
+    document.objects.insert(
+        pages_id,
+        Object::Dictionary(dictionary! {
+            "Type" => "Pages",
+            "Kids" => vec![page_id.into()],
+            "Count" => 1,
+        }),
+    );
+    let catalog_id = document.add_object(dictionary! {
+        "Type" => "Catalog",
+        "Pages" => pages_id,
+    });
+    document.trailer.set("Root", catalog_id);
+    document
+        .save(path)
+        .map_err(|e| anyhow!("Failed to write watermark: {}", e.to_string()))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FIXTURE: &[u8] = b"%PDF-1.4
+1 0 obj << /Type /Pages /Kids [2 0 R 3 0 R] /Count 2 >> endobj
+2 0 obj << /Type /Page >> endobj
+3 0 obj << /Type/Page >> endobj
+%%EOF
+";
+    const FIXTURE_SHA256: &str = "f8ffb9fd7224d5ce3315ab236ef8abc444686b6865d7a61193a3c1187a1c04aa";
+    const FIXTURE_MD5: &str = "emYm4jzECXJtYAAIwhToJw==";
+
+    #[test]
+    fn hashing_reader_matches_fixture() {
+        let mut reader = HashingReader::new(FIXTURE);
+        let mut contents = Vec::new();
+        // small reads, so that the hashes are fed in several chunks
+        let mut buf = [0; 16];
+        loop {
+            let length = reader.read(&mut buf).unwrap();
+            if length == 0 {
+                break;
+            }
+            contents.extend_from_slice(&buf[..length]);
+        }
+        assert_eq!(contents, FIXTURE);
+        let stats = reader.finish();
+        assert_eq!(stats.bytes, 143);
+        assert_eq!(stats.sha256, FIXTURE_SHA256);
+        assert_eq!(stats.md5, FIXTURE_MD5);
+    }
+
+    #[test]
+    fn output_stats_match_fixture() {
+        let stats = OutputStats::new(FIXTURE);
+        assert_eq!(stats.bytes, 143);
+        assert_eq!(stats.sha256, FIXTURE_SHA256);
+        assert_eq!(stats.md5, FIXTURE_MD5);
+        assert_eq!(stats.page_count, Some(2));
+    }
+}
@@ -0,0 +1,186 @@
+use anyhow::anyhow;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::string::ToString;
+
+use crate::Encryption;
+#[allow(unused_imports)]
+use crate::{debug, error, info, warn};
+
+const DEFAULT_BIT_LENGTH: u32 = 256;
+
+/// qpdf refuses the RC4-based 40-bit encryption by default, so only AES is offered.
+pub fn bit_length(encryption: &Encryption) -> anyhow::Result<u32> {
+    match encryption.bit_length.unwrap_or(DEFAULT_BIT_LENGTH) {
+        bit_length @ 128 | bit_length @ 256 => Ok(bit_length),
+        bit_length => Err(anyhow!(
+            "Unsupported encryption bit length {}, use 128 or 256",
+            bit_length
+        )),
+    }
+}
+
+/// Writes an encrypted copy of `input` to `output`. The passwords are passed to qpdf as arguments
+/// but never logged, and are masked in its output.
+pub fn encrypt(
+    qpdf_path: &str,
+    input: &Path,
+    output: &Path,
+    encryption: &Encryption,
+) -> anyhow::Result<()> {
+    let bit_length = bit_length(encryption)?;
+    let user_password = encryption.user_password.as_deref().unwrap_or_default();
+    info!("Encrypting PDF with {}-bit AES using qpdf", bit_length);
+    let result = Command::new(qpdf_path)
+        .stdin(Stdio::null())
+        .arg("--encrypt")
+        .arg(user_password)
+        .arg(&encryption.owner_password)
+        .arg(bit_length.to_string())
+        .arg("--")
+        .arg(input)
+        .arg(output)
+        .output()
+        .map_err(|e| anyhow!("Failed to run qpdf: {}", e.to_string()))?;
+    if !result.status.success() {
+        let mut stderr = String::from_utf8_lossy(&result.stderr).to_string();
+        for password in &[user_password, encryption.owner_password.as_str()] {
+            if !password.is_empty() {
+                stderr = stderr.replace(password, "***");
+            }
+        }
+        return Err(anyhow!(
+            "qpdf exited with {}: {}",
+            result.status,
+            stderr.trim()
+        ));
+    }
+    Ok(())
+}
+
+/// Concatenates the pages of `inputs` in order into `output`.
+pub fn merge(qpdf_path: &str, inputs: &[PathBuf], output: &Path) -> anyhow::Result<()> {
+    info!("Merging {} PDFs using qpdf", inputs.len());
+    let result = Command::new(qpdf_path)
+        .stdin(Stdio::null())
+        .arg("--empty")
+        .arg("--pages")
+        .args(inputs)
+        .arg("--")
+        .arg(output)
+        .output()
+        .map_err(|e| anyhow!("Failed to run qpdf: {}", e.to_string()))?;
+    if !result.status.success() {
+        return Err(anyhow!(
+            "qpdf exited with {}: {}",
+            result.status,
+            String::from_utf8_lossy(&result.stderr).trim()
+        ));
+    }
+    Ok(())
+}
+
+/// Parses a page range like `1,3-5` into 1-based inclusive ranges, in the given order.
+pub fn parse_page_range(page_range: &str) -> anyhow::Result<Vec<(u32, u32)>> {
+    let invalid = || anyhow!("Invalid pageRange {}, expected e.g. 1,3-5", page_range);
+    let mut ranges = Vec::new();
+    for part in page_range.split(',').map(str::trim) {
+        let mut bounds = part.splitn(2, '-').map(str::trim);
+        let start = bounds
+            .next()
+            .and_then(|start| start.parse::<u32>().ok())
+            .ok_or_else(invalid)?;
+        let end = match bounds.next() {
+            Some(end) => end.parse::<u32>().map_err(|_| invalid())?,
+            None => start,
+        };
+        if start == 0 || end < start {
+            return Err(invalid());
+        }
+        ranges.push((start, end));
+    }
+    Ok(ranges)
+}
+
+/// The number of pages of `input` according to `qpdf --show-npages`.
+pub fn page_count(qpdf_path: &str, input: &Path) -> anyhow::Result<u32> {
+    let result = Command::new(qpdf_path)
+        .stdin(Stdio::null())
+        .arg("--show-npages")
+        .arg(input)
+        .output()
+        .map_err(|e| anyhow!("Failed to run qpdf: {}", e.to_string()))?;
+    if !result.status.success() {
+        return Err(anyhow!(
+            "qpdf exited with {}: {}",
+            result.status,
+            String::from_utf8_lossy(&result.stderr).trim()
+        ));
+    }
+    String::from_utf8_lossy(&result.stdout)
+        .trim()
+        .parse()
+        .map_err(|e| anyhow!("Unexpected qpdf page count: {}", e))
+}
+
+/// Copies the pages in `ranges` of `input` into `output`.
+pub fn select_pages(
+    qpdf_path: &str,
+    input: &Path,
+    ranges: &[(u32, u32)],
+    output: &Path,
+) -> anyhow::Result<()> {
+    let ranges = ranges
+        .iter()
+        .map(|(start, end)| {
+            if start == end {
+                start.to_string()
+            } else {
+                format!("{}-{}", start, end)
+            }
+        })
+        .collect::<Vec<_>>()
+        .join(",");
+    info!("Selecting pages {} using qpdf", ranges);
+    let result = Command::new(qpdf_path)
+        .stdin(Stdio::null())
+        .arg("--empty")
+        .arg("--pages")
+        .arg(input)
+        .arg(&ranges)
+        .arg("--")
+        .arg(output)
+        .output()
+        .map_err(|e| anyhow!("Failed to run qpdf: {}", e.to_string()))?;
+    if !result.status.success() {
+        return Err(anyhow!(
+            "qpdf exited with {}: {}",
+            result.status,
+            String::from_utf8_lossy(&result.stderr).trim()
+        ));
+    }
+    Ok(())
+}
+
+/// Stamps the first page of `overlay` onto every page of `input`.
+pub fn overlay(qpdf_path: &str, input: &Path, overlay: &Path, output: &Path) -> anyhow::Result<()> {
+    info!("Stamping watermark using qpdf");
+    let result = Command::new(qpdf_path)
+        .stdin(Stdio::null())
+        .arg(input)
+        .arg("--overlay")
+        .arg(overlay)
+        .arg("--repeat=1")
+        .arg("--")
+        .arg(output)
+        .output()
+        .map_err(|e| anyhow!("Failed to run qpdf: {}", e.to_string()))?;
+    if !result.status.success() {
+        return Err(anyhow!(
+            "qpdf exited with {}: {}",
+            result.status,
+            String::from_utf8_lossy(&result.stderr).trim()
+        ));
+    }
+    Ok(())
+}
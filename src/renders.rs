@@ -0,0 +1,70 @@
+use anyhow::anyhow;
+use once_cell::sync::Lazy;
+use std::env;
+use std::string::ToString;
+use std::sync::{Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+#[allow(unused_imports)]
+use crate::{debug, error, info, warn};
+
+const DEFAULT_MAX_CONCURRENT_RENDERS: usize = 1;
+/// How long a render waits for a permit before giving up with `TooBusy`.
+const PERMIT_WAIT: Duration = Duration::from_secs(2);
+
+/// The number of renders running in this container.
+static RUNNING: Lazy<(Mutex<usize>, Condvar)> = Lazy::new(|| (Mutex::new(0), Condvar::new()));
+
+/// Held while wkhtmltopdf runs, see `acquire`.
+pub struct Permit;
+
+/// Waits briefly until fewer than `MAX_CONCURRENT_RENDERS` renders are running, so that a burst
+/// of invocations in a warm container can't run it out of memory.
+pub fn acquire() -> anyhow::Result<Permit> {
+    let max_renders = match env::var("MAX_CONCURRENT_RENDERS") {
+        Ok(max_renders) => match max_renders.parse::<usize>() {
+            Ok(max_renders) if max_renders > 0 => max_renders,
+            Ok(_) => return Err(anyhow!("Invalid MAX_CONCURRENT_RENDERS: must be positive")),
+            Err(e) => return Err(anyhow!("Invalid MAX_CONCURRENT_RENDERS: {}", e.to_string())),
+        },
+        Err(_) => DEFAULT_MAX_CONCURRENT_RENDERS,
+    };
+    let (running, released) = &*RUNNING;
+    let deadline = Instant::now() + PERMIT_WAIT;
+    let mut running = running.lock().unwrap_or_else(|e| e.into_inner());
+    while *running >= max_renders {
+        let now = Instant::now();
+        if now >= deadline {
+            return Err(anyhow!(
+                "Too busy, {} renders are already running, try again later",
+                *running
+            ));
+        }
+        running = released
+            .wait_timeout(running, deadline - now)
+            .unwrap_or_else(|e| e.into_inner())
+            .0;
+    }
+    *running += 1;
+    Ok(Permit)
+}
+
+/// Waits up to `timeout` until no renders are running, returning whether that's the case.
+pub fn wait_idle(timeout: Duration) -> bool {
+    let (running, released) = &*RUNNING;
+    let running = running.lock().unwrap_or_else(|e| e.into_inner());
+    let (running, _) = released
+        .wait_timeout_while(running, timeout, |running| *running > 0)
+        .unwrap_or_else(|e| e.into_inner());
+    *running == 0
+}
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        let (running, released) = &*RUNNING;
+        let mut running = running.lock().unwrap_or_else(|e| e.into_inner());
+        *running -= 1;
+        // wakes up `wait_idle` as well as the renders waiting for a permit
+        released.notify_all();
+    }
+}
@@ -0,0 +1,1794 @@
+use anyhow::anyhow;
+use chrono::Utc;
+use futures::future::{self, BoxFuture};
+use futures::StreamExt;
+use once_cell::sync::Lazy;
+use rand::Rng;
+use rusoto_core::credential::{
+    AwsCredentials, DefaultCredentialsProvider, ProvideAwsCredentials, StaticProvider,
+};
+use rusoto_core::request::{DispatchSignedRequestFuture, HttpDispatchError};
+use rusoto_core::signature::SignedRequest;
+use rusoto_core::{Client, DispatchSignedRequest, HttpClient, Region, RusotoError};
+use rusoto_s3::util::{PreSignedRequest, PreSignedRequestOption};
+use rusoto_s3::{
+    AbortMultipartUploadRequest, CompleteMultipartUploadRequest, CompletedMultipartUpload,
+    CompletedPart, CreateMultipartUploadRequest, GetObjectError, GetObjectOutput, GetObjectRequest,
+    HeadObjectError, HeadObjectOutput, HeadObjectRequest, ListObjectsV2Request, PutObjectError,
+    PutObjectOutput, PutObjectRequest, S3Client, UploadPartRequest, S3,
+};
+use rusoto_sts::{StsAssumeRoleSessionCredentialsProvider, StsClient};
+use std::collections::HashMap;
+use std::env;
+use std::fs;
+use std::future::Future;
+use std::io::{Read, Write};
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+use tokio::io::AsyncReadExt;
+use tokio::runtime::Runtime;
+use tokio::sync::Semaphore;
+use url::form_urlencoded;
+use uuid::Uuid;
+
+use crate::error::WithErrorCode;
+use crate::utils::runtime;
+#[allow(unused_imports)]
+use crate::{debug, error, info, warn};
+use crate::{Credentials, ErrorCode, OutputFormat, S3Details, S3Object};
+
+// S3 rejects multipart uploads with parts smaller than 5MB, except for the last one
+const MIN_PART_BYTES: usize = 5 * 1024 * 1024;
+const DEFAULT_MULTIPART_THRESHOLD: usize = 8 * 1024 * 1024;
+const DEFAULT_MAX_RETRIES: u32 = 3;
+const DEFAULT_BASE_DELAY_MS: u64 = 100;
+const MAX_DELAY_MS: u64 = 10_000;
+const DEFAULT_MAX_CONCURRENT_FETCHES: usize = 4;
+// SigV4 presigned URLs are valid for at most 7 days
+const MAX_PRESIGN_SECONDS: u64 = 7 * 24 * 60 * 60;
+const STORAGE_CLASSES: &[&str] = &[
+    "STANDARD",
+    "REDUCED_REDUNDANCY",
+    "STANDARD_IA",
+    "ONEZONE_IA",
+    "INTELLIGENT_TIERING",
+    "GLACIER",
+    "DEEP_ARCHIVE",
+    "OUTPOSTS",
+];
+const MAX_TAGS: usize = 10;
+const MAX_TAG_KEY_CHARS: usize = 128;
+const MAX_TAG_VALUE_CHARS: usize = 256;
+const MAX_KEY_BYTES: usize = 1024;
+// assumed role credentials are refreshed when they're about to expire within this many seconds
+const CREDENTIALS_REFRESH_MARGIN_SECS: i64 = 300;
+
+/// Temporary credentials of assumed roles, keyed by role ARN and external ID, kept for the
+/// lifetime of the container.
+static ASSUMED_CREDENTIALS: Lazy<Mutex<HashMap<String, AwsCredentials>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// Exponential backoff with full jitter for transient S3 failures, configured by `S3_MAX_RETRIES`
+/// and `S3_BASE_DELAY_MS`.
+struct RetryPolicy {
+    max_retries: u32,
+    base_delay_ms: u64,
+}
+
+impl RetryPolicy {
+    fn from_env() -> anyhow::Result<Self> {
+        let max_retries = match env::var("S3_MAX_RETRIES") {
+            Ok(max_retries) => max_retries
+                .parse::<u32>()
+                .map_err(|e| anyhow!("Invalid S3_MAX_RETRIES: {}", e.to_string()))?,
+            Err(_) => DEFAULT_MAX_RETRIES,
+        };
+        let base_delay_ms = match env::var("S3_BASE_DELAY_MS") {
+            Ok(base_delay_ms) => base_delay_ms
+                .parse::<u64>()
+                .map_err(|e| anyhow!("Invalid S3_BASE_DELAY_MS: {}", e.to_string()))?,
+            Err(_) => DEFAULT_BASE_DELAY_MS,
+        };
+        Ok(RetryPolicy {
+            max_retries,
+            base_delay_ms,
+        })
+    }
+
+    /// Runs the call until it succeeds, fails with a non-retryable error or runs out of retries.
+    /// Returns the result along with the number of attempts made.
+    #[allow(clippy::result_large_err)]
+    fn run<T, E, F>(
+        &self,
+        runtime: &mut Runtime,
+        description: &str,
+        mut call: impl FnMut() -> F,
+    ) -> Result<(T, u32), RusotoError<E>>
+    where
+        F: Future<Output = Result<T, RusotoError<E>>>,
+        E: std::error::Error + 'static,
+    {
+        let mut attempt = 1;
+        loop {
+            match runtime.block_on(call()) {
+                Ok(result) => return Ok((result, attempt)),
+                Err(e) if attempt <= self.max_retries && is_retryable(&e) => {
+                    let max_delay_ms = self
+                        .base_delay_ms
+                        .saturating_mul(1 << (attempt - 1).min(16))
+                        .min(MAX_DELAY_MS);
+                    let delay_ms = rand::thread_rng().gen_range(0, max_delay_ms + 1);
+                    warn!(
+                        "{} failed on attempt {}, retrying in {}ms: {}",
+                        description, attempt, delay_ms, e
+                    );
+                    thread::sleep(Duration::from_millis(delay_ms));
+                    attempt += 1;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+}
+
+/// Throttling, 5xx and network errors are worth retrying, whereas e.g. AccessDenied or
+/// NoSuchBucket won't go away on their own.
+fn is_retryable<E>(error: &RusotoError<E>) -> bool {
+    match error {
+        RusotoError::HttpDispatch(_) => true,
+        RusotoError::Unknown(response) => {
+            let status = response.status.as_u16();
+            status >= 500 || status == 429 || response.body_as_str().contains("SlowDown")
+        }
+        _ => false,
+    }
+}
+
+/// What S3 reports back about the uploaded object, either may be missing on S3-compatible stores
+/// or unversioned buckets.
+pub struct UploadedObject {
+    pub etag: Option<String>,
+    pub version_id: Option<String>,
+}
+
+/// Where single PUT uploads end up. Apart from S3 itself, this allows capturing the requests
+/// `upload_to` builds.
+pub trait ObjectStore {
+    fn put(
+        &self,
+        request: PutObjectRequest,
+    ) -> BoxFuture<'_, Result<PutObjectOutput, RusotoError<PutObjectError>>>;
+
+    fn head(
+        &self,
+        request: HeadObjectRequest,
+    ) -> BoxFuture<'_, Result<HeadObjectOutput, RusotoError<HeadObjectError>>>;
+}
+
+impl ObjectStore for S3Client {
+    fn put(
+        &self,
+        request: PutObjectRequest,
+    ) -> BoxFuture<'_, Result<PutObjectOutput, RusotoError<PutObjectError>>> {
+        self.put_object(request)
+    }
+
+    fn head(
+        &self,
+        request: HeadObjectRequest,
+    ) -> BoxFuture<'_, Result<HeadObjectOutput, RusotoError<HeadObjectError>>> {
+        self.head_object(request)
+    }
+}
+
+pub fn upload(
+    contents: Vec<u8>,
+    s3_details: &S3Details,
+    content_type: &str,
+) -> anyhow::Result<UploadedObject> {
+    let mut runtime = runtime()?;
+    let s3 = client(&mut runtime, s3_details)?;
+    upload_to(&s3, &mut runtime, contents, s3_details, content_type)
+}
+
+/// Uploads with a single PUT to `store`, retrying like any other S3 call.
+pub fn upload_to(
+    store: &dyn ObjectStore,
+    runtime: &mut Runtime,
+    contents: Vec<u8>,
+    s3_details: &S3Details,
+    content_type: &str,
+) -> anyhow::Result<UploadedObject> {
+    check_not_exists_in(store, runtime, s3_details)?;
+    let server_side_encryption = server_side_encryption(s3_details)?;
+    let tagging = tagging(s3_details)?;
+    let storage_class = storage_class(s3_details)?;
+    // S3 rejects the upload if the bytes it received don't match
+    let content_md5 = base64::encode(md5::compute(&contents).0);
+    let content_type = s3_details.content_type.as_deref().unwrap_or(content_type);
+    // the body is consumed by each attempt, so the request has to be rebuilt for retries
+    let put_request = || PutObjectRequest {
+        bucket: s3_details.bucket.clone(),
+        key: s3_details.object_key.clone(),
+        content_type: Some(content_type.to_owned()),
+        content_md5: Some(content_md5.clone()),
+        server_side_encryption: server_side_encryption.clone(),
+        ssekms_key_id: s3_details.kms_key_id.clone(),
+        tagging: tagging.clone(),
+        cache_control: s3_details.cache_control.clone(),
+        expires: s3_details.expires.clone(),
+        storage_class: storage_class.clone(),
+        content_disposition: s3_details
+            .download_filename
+            .as_deref()
+            .map(content_disposition),
+        body: Some(contents.clone().into()),
+        ..Default::default()
+    };
+
+    let retry_policy = RetryPolicy::from_env()?;
+    let (put_response, attempts) = retry_policy
+        .run(runtime, "PutObject", || store.put(put_request()))
+        .map_err(|e| upload_error(e, s3_details))?;
+    info!(
+        "Uploaded output to s3://{}/{} after {} attempt(s)",
+        s3_details.bucket, s3_details.object_key, attempts
+    );
+
+    Ok(UploadedObject {
+        etag: put_response.e_tag,
+        version_id: put_response.version_id,
+    })
+}
+
+pub fn object_exists(s3_details: &S3Details) -> anyhow::Result<bool> {
+    let mut runtime = runtime()?;
+    let s3 = client(&mut runtime, s3_details)?;
+    object_exists_in(&s3, &mut runtime, s3_details)
+}
+
+fn object_exists_in(
+    store: &dyn ObjectStore,
+    runtime: &mut Runtime,
+    s3_details: &S3Details,
+) -> anyhow::Result<bool> {
+    let head_request = HeadObjectRequest {
+        bucket: s3_details.bucket.clone(),
+        key: s3_details.object_key.clone(),
+        ..Default::default()
+    };
+
+    let retry_policy = RetryPolicy::from_env()?;
+    match retry_policy.run(runtime, "HeadObject", || store.head(head_request.clone())) {
+        Ok(_) => Ok(true),
+        Err(RusotoError::Service(HeadObjectError::NoSuchKey(_))) => Ok(false),
+        // HEAD responses have no body to tell the error apart, so a 404 is usually all we get
+        Err(RusotoError::Unknown(ref response)) if response.status.as_u16() == 404 => Ok(false),
+        Err(e) => Err(anyhow!(
+            "Failed to check whether s3://{}/{} exists: {}",
+            s3_details.bucket,
+            s3_details.object_key,
+            e.to_string()
+        )),
+    }
+}
+
+/// Best-effort check for `failIfExists`, a HEAD before the PUT. rusoto's PutObjectRequest has no
+/// `If-None-Match`, so a concurrent writer may still slip in between the check and the upload.
+fn check_not_exists(s3_details: &S3Details) -> anyhow::Result<()> {
+    if !s3_details.fail_if_exists {
+        return Ok(());
+    }
+    let mut runtime = runtime()?;
+    let s3 = client(&mut runtime, s3_details)?;
+    check_not_exists_in(&s3, &mut runtime, s3_details)
+}
+
+fn check_not_exists_in(
+    store: &dyn ObjectStore,
+    runtime: &mut Runtime,
+    s3_details: &S3Details,
+) -> anyhow::Result<()> {
+    if s3_details.fail_if_exists && object_exists_in(store, runtime, s3_details)? {
+        return Err(anyhow!(
+            "s3://{}/{} already existed when checked before uploading, and failIfExists is set",
+            s3_details.bucket,
+            s3_details.object_key
+        ));
+    }
+    Ok(())
+}
+
+/// An upload whose contents have been fully streamed to S3, but which only becomes visible once
+/// completed.
+pub enum PendingUpload {
+    /// Output below the multipart threshold, uploaded with a single PUT
+    Single(Vec<u8>),
+    Multipart {
+        upload_id: String,
+        parts: Vec<CompletedPart>,
+    },
+}
+
+impl PendingUpload {
+    pub fn complete(self, s3_details: &S3Details) -> anyhow::Result<UploadedObject> {
+        match self {
+            PendingUpload::Single(contents) => {
+                if contents.is_empty() {
+                    return Err(anyhow!("Failed to read PDF output"));
+                }
+                // only PDFs are streamed
+                upload(contents, s3_details, OutputFormat::Pdf.content_type())
+            }
+            PendingUpload::Multipart { upload_id, parts } => {
+                let complete_request = CompleteMultipartUploadRequest {
+                    bucket: s3_details.bucket.clone(),
+                    key: s3_details.object_key.clone(),
+                    upload_id,
+                    multipart_upload: Some(CompletedMultipartUpload { parts: Some(parts) }),
+                    ..Default::default()
+                };
+                let retry_policy = RetryPolicy::from_env()?;
+                let mut runtime = runtime()?;
+                let s3 = client(&mut runtime, s3_details)?;
+                let (complete_response, attempts) = retry_policy
+                    .run(&mut runtime, "CompleteMultipartUpload", || {
+                        s3.complete_multipart_upload(complete_request.clone())
+                    })
+                    .map_err(|e| upload_error(e, s3_details))?;
+                info!(
+                    "Completed multipart upload to s3://{}/{} after {} attempt(s)",
+                    s3_details.bucket, s3_details.object_key, attempts
+                );
+                Ok(UploadedObject {
+                    etag: complete_response.e_tag,
+                    version_id: complete_response.version_id,
+                })
+            }
+        }
+    }
+
+    pub fn abort(self, s3_details: &S3Details) -> anyhow::Result<()> {
+        if let PendingUpload::Multipart { upload_id, .. } = self {
+            abort_multipart_upload(upload_id, s3_details)?;
+        }
+        Ok(())
+    }
+}
+
+/// Streams the output into a multipart upload, so that at most one part is held in memory at a
+/// time. Output smaller than `S3_MULTIPART_THRESHOLD` is buffered for a single PUT instead.
+pub fn stream_upload(
+    mut reader: impl Read,
+    s3_details: &S3Details,
+) -> anyhow::Result<PendingUpload> {
+    let part_size = match env::var("S3_MULTIPART_THRESHOLD") {
+        Ok(threshold) => threshold
+            .parse::<usize>()
+            .map_err(|e| anyhow!("Invalid S3_MULTIPART_THRESHOLD: {}", e.to_string()))?
+            .max(MIN_PART_BYTES),
+        Err(_) => DEFAULT_MULTIPART_THRESHOLD,
+    };
+
+    let mut chunk = read_chunk(&mut reader, part_size)?;
+    if chunk.len() < part_size {
+        return Ok(PendingUpload::Single(chunk));
+    }
+
+    check_not_exists(s3_details)?;
+    let create_request = CreateMultipartUploadRequest {
+        bucket: s3_details.bucket.clone(),
+        key: s3_details.object_key.clone(),
+        content_type: Some(
+            s3_details
+                .content_type
+                .clone()
+                .unwrap_or_else(|| OutputFormat::Pdf.content_type().to_owned()),
+        ),
+        server_side_encryption: server_side_encryption(s3_details)?,
+        ssekms_key_id: s3_details.kms_key_id.clone(),
+        tagging: tagging(s3_details)?,
+        cache_control: s3_details.cache_control.clone(),
+        expires: s3_details.expires.clone(),
+        storage_class: storage_class(s3_details)?,
+        content_disposition: s3_details
+            .download_filename
+            .as_deref()
+            .map(content_disposition),
+        ..Default::default()
+    };
+    let retry_policy = RetryPolicy::from_env()?;
+    let mut runtime = runtime()?;
+    let s3 = client(&mut runtime, s3_details)?;
+    let (create_response, _) = retry_policy
+        .run(&mut runtime, "CreateMultipartUpload", || {
+            s3.create_multipart_upload(create_request.clone())
+        })
+        .map_err(|e| upload_error(e, s3_details))?;
+    let upload_id = create_response
+        .upload_id
+        .ok_or_else(|| anyhow!("S3 did not return a multipart upload ID"))?;
+    info!(
+        "Started multipart upload {} to s3://{}/{}",
+        upload_id, s3_details.bucket, s3_details.object_key
+    );
+
+    let mut parts = Vec::new();
+    let result = loop {
+        let part_number = parts.len() as i64 + 1;
+        let upload_request = || UploadPartRequest {
+            bucket: s3_details.bucket.clone(),
+            key: s3_details.object_key.clone(),
+            upload_id: upload_id.clone(),
+            part_number,
+            content_length: Some(chunk.len() as i64),
+            content_md5: Some(base64::encode(md5::compute(&chunk).0)),
+            body: Some(chunk.clone().into()),
+            ..Default::default()
+        };
+        match retry_policy.run(&mut runtime, "UploadPart", || {
+            s3.upload_part(upload_request())
+        }) {
+            Ok((upload_response, _)) => parts.push(CompletedPart {
+                e_tag: upload_response.e_tag,
+                part_number: Some(part_number),
+            }),
+            Err(e) => {
+                break Err(anyhow!(
+                    "Failed to upload part {}: {}",
+                    part_number,
+                    upload_error(e, s3_details)
+                ))
+            }
+        }
+        debug!("Uploaded part {} of multipart upload", part_number);
+        chunk = match read_chunk(&mut reader, part_size) {
+            Ok(chunk) if chunk.is_empty() => break Ok(()),
+            Ok(chunk) => chunk,
+            Err(e) => break Err(e),
+        };
+    };
+    drop(runtime);
+    if let Err(e) = result {
+        if let Err(e) = abort_multipart_upload(upload_id, s3_details) {
+            error!("{}", e);
+        }
+        return Err(e);
+    }
+
+    Ok(PendingUpload::Multipart { upload_id, parts })
+}
+
+/// Explains the common reasons of failed uploads, which S3 only reports as an error code in the
+/// XML body of the response.
+fn upload_error<E: std::error::Error + 'static>(
+    error: RusotoError<E>,
+    s3_details: &S3Details,
+) -> anyhow::Error {
+    let response = match &error {
+        RusotoError::Credentials(e) => return anyhow!("No usable AWS credentials: {}", e),
+        RusotoError::Unknown(response) => response,
+        _ => return anyhow!(error.to_string()),
+    };
+    let body = response.body_as_str();
+    let code = body
+        .split("<Code>")
+        .nth(1)
+        .and_then(|rest| rest.split("</Code>").next())
+        .unwrap_or_default();
+    match code {
+        "NoSuchBucket" => anyhow!("Bucket {} does not exist", s3_details.bucket),
+        "AccessDenied" => anyhow!(
+            "Access to s3://{}/{} denied, check s3:PutObject (and kms:GenerateDataKey with SSE-KMS) permissions",
+            s3_details.bucket,
+            s3_details.object_key
+        ),
+        "InvalidAccessKeyId" | "SignatureDoesNotMatch" | "ExpiredToken" | "InvalidToken" => {
+            anyhow!("S3 rejected the credentials: {}", code)
+        }
+        "BadDigest" | "InvalidDigest" => anyhow!(
+            "Integrity check failed, the bytes S3 received don't match the Content-MD5 of the output"
+        ),
+        "SlowDown" => anyhow!("S3 is throttling uploads, even after retrying"),
+        _ if response.status.as_u16() == 429 || response.status.as_u16() == 503 => {
+            anyhow!("S3 is throttling uploads, even after retrying")
+        }
+        "" => anyhow!("S3 responded with {}: {}", response.status, body.trim()),
+        _ => anyhow!("S3 responded with {} {}", response.status, code),
+    }
+}
+
+fn read_chunk(reader: &mut impl Read, size: usize) -> anyhow::Result<Vec<u8>> {
+    let mut chunk = Vec::with_capacity(size);
+    reader
+        .take(size as u64)
+        .read_to_end(&mut chunk)
+        .map_err(|e| anyhow!("Failed to read PDF output: {}", e.to_string()))?;
+    Ok(chunk)
+}
+
+fn abort_multipart_upload(upload_id: String, s3_details: &S3Details) -> anyhow::Result<()> {
+    let abort_request = AbortMultipartUploadRequest {
+        bucket: s3_details.bucket.clone(),
+        key: s3_details.object_key.clone(),
+        upload_id: upload_id.clone(),
+        ..Default::default()
+    };
+    let retry_policy = RetryPolicy::from_env()?;
+    let mut runtime = runtime()?;
+    let s3 = client(&mut runtime, s3_details)?;
+    retry_policy
+        .run(&mut runtime, "AbortMultipartUpload", || {
+            s3.abort_multipart_upload(abort_request.clone())
+        })
+        .map_err(|e| anyhow!("Failed to abort multipart upload {}: {}", upload_id, e))?;
+    warn!("Aborted multipart upload {}", upload_id);
+    Ok(())
+}
+
+/// A KMS key implies SSE-KMS, whereas a bare aws:kms falls back to the bucket's default key.
+fn server_side_encryption(s3_details: &S3Details) -> anyhow::Result<Option<String>> {
+    match (&s3_details.sse, &s3_details.kms_key_id) {
+        (Some(sse), _) if sse != "AES256" && sse != "aws:kms" => {
+            Err(anyhow!("Unsupported server-side encryption: {}", sse))
+        }
+        (Some(sse), Some(_)) if sse != "aws:kms" => Err(anyhow!(
+            "KMS key ID requires aws:kms server-side encryption"
+        )),
+        (Some(sse), _) => Ok(Some(sse.clone())),
+        (None, Some(_)) => Ok(Some("aws:kms".to_owned())),
+        (None, None) => Ok(None),
+    }
+}
+
+pub fn storage_class(s3_details: &S3Details) -> anyhow::Result<Option<String>> {
+    match &s3_details.storage_class {
+        Some(storage_class) if !STORAGE_CLASSES.contains(&storage_class.as_str()) => Err(anyhow!(
+            "Unsupported storage class {}, expected one of {}",
+            storage_class,
+            STORAGE_CLASSES.join(", ")
+        )),
+        storage_class => Ok(storage_class.clone()),
+    }
+}
+
+/// `expires` is sent as is, so it has to be an HTTP-date already, e.g.
+/// `Wed, 21 Oct 2015 07:28:00 GMT`.
+pub fn check_expires(s3_details: &S3Details) -> anyhow::Result<()> {
+    if let Some(expires) = &s3_details.expires {
+        chrono::DateTime::parse_from_rfc2822(expires)
+            .map_err(|e| anyhow!("Invalid expires {}: {}", expires, e.to_string()))?;
+    }
+    Ok(())
+}
+
+/// Only the syntax of `contentType` is checked: `type/subtype`, optionally followed by
+/// `; name=value` parameters.
+pub fn check_content_type(s3_details: &S3Details) -> anyhow::Result<()> {
+    if let Some(content_type) = &s3_details.content_type {
+        let mut parts = content_type.split(';');
+        let mut essence = parts.next().unwrap_or_default().trim().splitn(2, '/');
+        let valid = match (essence.next(), essence.next()) {
+            (Some(media_type), Some(subtype)) => is_token(media_type) && is_token(subtype),
+            _ => false,
+        } && parts.all(|parameter| {
+            let mut parameter = parameter.trim().splitn(2, '=');
+            match (parameter.next(), parameter.next()) {
+                (Some(name), Some(value)) => {
+                    is_token(name) && !value.is_empty() && !value.chars().any(|c| c.is_control())
+                }
+                _ => false,
+            }
+        });
+        if !valid {
+            return Err(anyhow!(
+                "Invalid contentType {:?}, expected e.g. application/pdf",
+                content_type
+            ));
+        }
+    }
+    Ok(())
+}
+
+fn is_token(s: &str) -> bool {
+    !s.is_empty()
+        && s.chars()
+            .all(|c| c.is_ascii_alphanumeric() || "!#$&+-.^_`|~".contains(c))
+}
+
+pub fn check_presign_seconds(s3_details: &S3Details) -> anyhow::Result<()> {
+    match s3_details.presign_seconds {
+        Some(seconds) if seconds == 0 || seconds > MAX_PRESIGN_SECONDS => Err(anyhow!(
+            "presignSeconds must be between 1 and {}",
+            MAX_PRESIGN_SECONDS
+        )),
+        _ => Ok(()),
+    }
+}
+
+/// Signs a GET of the uploaded object with the same credentials, region and endpoint used for
+/// the upload. The URL stops working early if those credentials expire first.
+pub fn presign(s3_details: &S3Details, expires_in: Duration) -> anyhow::Result<String> {
+    let region = region(s3_details.region.as_deref(), s3_details.endpoint.as_deref())?;
+    let mut runtime = runtime()?;
+    let credentials = match (&s3_details.credentials, &s3_details.assume_role_arn) {
+        (Some(credentials), _) => static_credentials(credentials),
+        (None, Some(role_arn)) => assumed_credentials(
+            &mut runtime,
+            role_arn,
+            s3_details.external_id.as_deref(),
+            &region,
+        )?,
+        (None, None) => runtime
+            .block_on(DefaultCredentialsProvider::new()?.credentials())
+            .map_err(|e| anyhow!("Failed to load credentials: {}", e.to_string()))?,
+    };
+    let get_request = GetObjectRequest {
+        bucket: s3_details.bucket.clone(),
+        key: s3_details.object_key.clone(),
+        ..Default::default()
+    };
+    Ok(get_request.get_presigned_url(
+        &s3_region(s3_details, &region)?,
+        &credentials,
+        &PreSignedRequestOption { expires_in },
+    ))
+}
+
+/// Encodes the tags as the query string S3 expects, within S3's limits on tags.
+pub fn tagging(s3_details: &S3Details) -> anyhow::Result<Option<String>> {
+    if s3_details.tags.is_empty() {
+        return Ok(None);
+    }
+    if s3_details.tags.len() > MAX_TAGS {
+        return Err(anyhow!("At most {} tags are allowed", MAX_TAGS));
+    }
+    let valid_chars = |s: &str| {
+        s.chars()
+            .all(|c| c.is_alphanumeric() || c == ' ' || "+-=._:/@".contains(c))
+    };
+    let mut pairs = Vec::new();
+    for (key, value) in &s3_details.tags {
+        if key.is_empty() || key.chars().count() > MAX_TAG_KEY_CHARS {
+            return Err(anyhow!(
+                "Tag key {} must be 1 to {} characters long",
+                key,
+                MAX_TAG_KEY_CHARS
+            ));
+        }
+        if value.chars().count() > MAX_TAG_VALUE_CHARS {
+            return Err(anyhow!(
+                "Value of tag {} must be at most {} characters long",
+                key,
+                MAX_TAG_VALUE_CHARS
+            ));
+        }
+        if !valid_chars(key) || !valid_chars(value) {
+            return Err(anyhow!("Tag {}={} contains invalid characters", key, value));
+        }
+        if key.to_lowercase().starts_with("aws:") {
+            return Err(anyhow!("Tag key {} uses the reserved aws: prefix", key));
+        }
+        if s3_details.tags.iter().filter(|(k, _)| k == key).count() > 1 {
+            return Err(anyhow!("Duplicate tag key {}", key));
+        }
+        pairs.push(format!("{}={}", encode_tag(key), encode_tag(value)));
+    }
+    Ok(Some(pairs.join("&")))
+}
+
+fn encode_tag(s: &str) -> String {
+    // byte_serialize encodes spaces as +, which S3 would keep literally
+    form_urlencoded::byte_serialize(s.as_bytes())
+        .collect::<String>()
+        .replace('+', "%20")
+}
+
+/// Downloads the objects concurrently, at most `MAX_CONCURRENT_FETCHES` (4 by default) at a time,
+/// and returns their contents in the same order.
+pub fn download_all(s3_objects: &[&S3Object]) -> anyhow::Result<Vec<Vec<u8>>> {
+    let semaphore = Semaphore::new(max_concurrent_fetches()?);
+    let mut runtime = runtime()?;
+    runtime.block_on(async {
+        future::try_join_all(s3_objects.iter().map(|&s3_object| {
+            let semaphore = &semaphore;
+            async move {
+                let _permit = semaphore.acquire().await;
+                download(s3_object).await
+            }
+        }))
+        .await
+    })
+}
+
+/// Like `download_all`, but writes each object to its path as it arrives instead of keeping it in
+/// memory. Returns the number of bytes written per object.
+pub fn download_all_to(downloads: &[(&S3Object, &Path)]) -> anyhow::Result<Vec<u64>> {
+    let semaphore = Semaphore::new(max_concurrent_fetches()?);
+    let mut runtime = runtime()?;
+    runtime.block_on(async {
+        future::try_join_all(downloads.iter().map(|&(s3_object, path)| {
+            let semaphore = &semaphore;
+            async move {
+                let _permit = semaphore.acquire().await;
+                download_to(s3_object, path).await
+            }
+        }))
+        .await
+    })
+}
+
+fn max_concurrent_fetches() -> anyhow::Result<usize> {
+    match env::var("MAX_CONCURRENT_FETCHES") {
+        Ok(max_concurrency) => match max_concurrency.parse::<usize>() {
+            Ok(max_concurrency) if max_concurrency > 0 => Ok(max_concurrency),
+            Ok(_) => Err(anyhow!("Invalid MAX_CONCURRENT_FETCHES: must be positive")),
+            Err(e) => Err(anyhow!("Invalid MAX_CONCURRENT_FETCHES: {}", e.to_string())),
+        },
+        Err(_) => Ok(DEFAULT_MAX_CONCURRENT_FETCHES),
+    }
+}
+
+async fn download(s3_object: &S3Object) -> anyhow::Result<Vec<u8>> {
+    let get_response = get_object(s3_object).await?;
+    let mut contents = Vec::new();
+    if let Some(body) = get_response.body {
+        body.into_async_read().read_to_end(&mut contents).await?;
+    }
+    info!(
+        "Downloaded {} bytes from s3://{}/{}",
+        contents.len(),
+        s3_object.bucket,
+        s3_object.object_key
+    );
+
+    Ok(contents)
+}
+
+async fn download_to(s3_object: &S3Object, path: &Path) -> anyhow::Result<u64> {
+    let get_response = get_object(s3_object).await?;
+    let mut file = fs::File::create(path)
+        .map_err(|e| anyhow!("Failed to create {}: {}", path.display(), e.to_string()))?;
+    let mut written = 0;
+    if let Some(mut body) = get_response.body {
+        while let Some(chunk) = body.next().await {
+            let chunk = chunk.map_err(|e| {
+                anyhow!(
+                    "Failed to download S3 object s3://{}/{}: {}",
+                    s3_object.bucket,
+                    s3_object.object_key,
+                    e.to_string()
+                )
+            })?;
+            file.write_all(&chunk)
+                .map_err(|e| anyhow!("Failed to write {}: {}", path.display(), e.to_string()))?;
+            written += chunk.len() as u64;
+        }
+    }
+    info!(
+        "Downloaded {} bytes from s3://{}/{} to {}",
+        written,
+        s3_object.bucket,
+        s3_object.object_key,
+        path.display()
+    );
+
+    Ok(written)
+}
+
+async fn get_object(s3_object: &S3Object) -> anyhow::Result<GetObjectOutput> {
+    let get_request = GetObjectRequest {
+        bucket: s3_object.bucket.clone(),
+        key: s3_object.object_key.clone(),
+        ..Default::default()
+    };
+
+    let s3 = S3Client::new(region(
+        s3_object.region.as_deref(),
+        s3_object.endpoint.as_deref(),
+    )?);
+    s3.get_object(get_request).await.map_err(|e| match e {
+        RusotoError::Service(GetObjectError::NoSuchKey(_)) => anyhow!(
+            "S3 object s3://{}/{} does not exist",
+            s3_object.bucket,
+            s3_object.object_key
+        ),
+        RusotoError::Unknown(ref response) if response.status.as_u16() == 403 => anyhow!(
+            "Access denied to S3 object s3://{}/{}",
+            s3_object.bucket,
+            s3_object.object_key
+        ),
+        RusotoError::Unknown(ref response) if response.status.as_u16() == 404 => anyhow!(
+            "S3 object s3://{}/{} does not exist",
+            s3_object.bucket,
+            s3_object.object_key
+        ),
+        e => anyhow!(
+            "Failed to download S3 object s3://{}/{}: {}",
+            s3_object.bucket,
+            s3_object.object_key,
+            e.to_string()
+        ),
+    })
+}
+
+/// Splits an `s3://bucket/key` URI into the bucket and the key, which may be empty.
+pub fn parse_uri(uri: &str) -> anyhow::Result<(String, String)> {
+    let rest = uri
+        .strip_prefix("s3://")
+        .ok_or_else(|| anyhow!("Invalid S3 URI {}, expected s3://bucket/key", uri))?;
+    let mut parts = rest.splitn(2, '/');
+    match (parts.next(), parts.next()) {
+        (Some(bucket), key) if !bucket.is_empty() => {
+            Ok((bucket.to_owned(), key.unwrap_or_default().to_owned()))
+        }
+        _ => Err(anyhow!("Invalid S3 URI {}, expected s3://bucket/key", uri)),
+    }
+}
+
+/// Lists the keys under `prefix` in `region`, or the default region, following continuation
+/// tokens.
+pub fn list_objects(
+    region_name: Option<&str>,
+    bucket: &str,
+    prefix: &str,
+) -> anyhow::Result<Vec<String>> {
+    let retry_policy = RetryPolicy::from_env()?;
+    let mut runtime = runtime()?;
+    let s3 = S3Client::new(region(region_name, None)?);
+    let mut keys = Vec::new();
+    let mut continuation_token = None;
+    loop {
+        let list_request = ListObjectsV2Request {
+            bucket: bucket.to_owned(),
+            prefix: Some(prefix.to_owned()),
+            continuation_token: continuation_token.clone(),
+            ..Default::default()
+        };
+        let (list_response, _) = retry_policy.run(&mut runtime, "ListObjectsV2", || {
+            s3.list_objects_v2(list_request.clone())
+        })?;
+        keys.extend(
+            list_response
+                .contents
+                .unwrap_or_default()
+                .into_iter()
+                .filter_map(|object| object.key),
+        );
+        match list_response.next_continuation_token {
+            Some(token) if list_response.is_truncated == Some(true) => {
+                continuation_token = Some(token)
+            }
+            _ => break,
+        }
+    }
+    Ok(keys)
+}
+
+/// Escapes a literal key, so that `expand_key_template` returns it unchanged.
+pub fn escape_key_template(key: &str) -> String {
+    key.replace('{', "{{")
+}
+
+/// Expands the placeholders in an object key template, see README for the supported ones. `{{`
+/// is a literal `{`.
+pub fn expand_key_template(template: &str, extension: &str) -> anyhow::Result<String> {
+    let now = Utc::now();
+    let mut key = String::new();
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        key.push_str(&rest[..start]);
+        // `{{` is a literal brace
+        if rest[start + 1..].starts_with('{') {
+            key.push('{');
+            rest = &rest[start + 2..];
+            continue;
+        }
+        let end = rest[start..]
+            .find('}')
+            .ok_or_else(|| anyhow!("Unterminated placeholder in object key {}", template))?;
+        match &rest[start + 1..start + end] {
+            "uuid" => key.push_str(&Uuid::new_v4().to_string()),
+            "date" => key.push_str(&now.format("%Y-%m-%d").to_string()),
+            "timestamp" => key.push_str(&now.timestamp().to_string()),
+            "ext" => key.push_str(extension),
+            placeholder => {
+                return Err(anyhow!(
+                    "Unknown placeholder {{{}}} in object key {}",
+                    placeholder,
+                    template
+                ))
+            }
+        }
+        rest = &rest[start + end + 1..];
+    }
+    key.push_str(rest);
+    Ok(key)
+}
+
+/// Builds a client with the Lambda's own credentials, with the temporary credentials of
+/// `assumeRoleArn` for cross-account uploads, or with the request's own `credentials`.
+fn client(runtime: &mut Runtime, s3_details: &S3Details) -> anyhow::Result<S3Client> {
+    let region = region(s3_details.region.as_deref(), s3_details.endpoint.as_deref())?;
+    if let Some(credentials) = &s3_details.credentials {
+        return new_client(
+            StaticProvider::from(static_credentials(credentials)),
+            s3_details,
+            s3_region(s3_details, &region)?,
+        );
+    }
+    match &s3_details.assume_role_arn {
+        Some(role_arn) => {
+            let credentials = assumed_credentials(
+                runtime,
+                role_arn,
+                s3_details.external_id.as_deref(),
+                &region,
+            )?;
+            new_client(
+                StaticProvider::from(credentials),
+                s3_details,
+                s3_region(s3_details, &region)?,
+            )
+        }
+        None if s3_details.use_accelerate => new_client(
+            DefaultCredentialsProvider::new()?,
+            s3_details,
+            s3_region(s3_details, &region)?,
+        ),
+        None => Ok(S3Client::new(s3_region(s3_details, &region)?)),
+    }
+}
+
+fn static_credentials(credentials: &Credentials) -> AwsCredentials {
+    AwsCredentials::new(
+        credentials.access_key_id.clone(),
+        credentials.secret_access_key.clone(),
+        credentials.session_token.clone(),
+        None,
+    )
+}
+
+fn assumed_credentials(
+    runtime: &mut Runtime,
+    role_arn: &str,
+    external_id: Option<&str>,
+    region: &Region,
+) -> anyhow::Result<AwsCredentials> {
+    let cache_key = format!("{}|{}", role_arn, external_id.unwrap_or_default());
+    let mut cache = ASSUMED_CREDENTIALS
+        .lock()
+        .map_err(|_| anyhow!("Failed to lock credentials cache"))?;
+    if let Some(credentials) = cache.get(&cache_key) {
+        let fresh = match credentials.expires_at() {
+            Some(expires_at) => {
+                (*expires_at - Utc::now()).num_seconds() > CREDENTIALS_REFRESH_MARGIN_SECS
+            }
+            None => true,
+        };
+        if fresh {
+            debug!("Reusing cached credentials of {}", role_arn);
+            return Ok(credentials.clone());
+        }
+    }
+
+    let provider = StsAssumeRoleSessionCredentialsProvider::new(
+        StsClient::new(region.clone()),
+        role_arn.to_owned(),
+        "wkhtmltopdf-lambda".to_owned(),
+        external_id.map(ToOwned::to_owned),
+        None,
+        None,
+        None,
+    );
+    let credentials = runtime
+        .block_on(provider.credentials())
+        .map_err(|e| anyhow!("Failed to assume role {}: {}", role_arn, e.to_string()))?;
+    info!("Assumed role {}", role_arn);
+    cache.insert(cache_key, credentials.clone());
+
+    Ok(credentials)
+}
+
+/// Checks the bucket and object key against S3's naming rules, so that mistakes are reported
+/// before rusoto fails on them, or quietly creates an odd key. S3-compatible stores often relax
+/// the bucket rules, so with a custom endpoint only obviously broken bucket names are refused.
+pub fn check_location(s3_details: &S3Details) -> anyhow::Result<()> {
+    let bucket = s3_details.bucket.as_str();
+    let custom_endpoint = s3_details.endpoint.is_some() || env::var("S3_ENDPOINT").is_ok();
+    if s3_details.credentials.is_some() {
+        // request-embedded credentials are meant for local stores, not for real AWS
+        if env::var("S3_ENDPOINT").is_err() {
+            return Err(anyhow!(
+                "credentials are only accepted when S3_ENDPOINT is set"
+            ));
+        }
+        let region = region(s3_details.region.as_deref(), s3_details.endpoint.as_deref())?;
+        check_credentials_region(&s3_region(s3_details, &region)?)?;
+        if s3_details.assume_role_arn.is_some() {
+            return Err(anyhow!("credentials can't be combined with assumeRoleArn"));
+        }
+    }
+    if bucket.is_empty() {
+        return Err(anyhow!("Bucket name is empty"));
+    }
+    if bucket
+        .chars()
+        .any(|c| c == '/' || c.is_whitespace() || c.is_control())
+    {
+        return Err(anyhow!(
+            "Bucket name {:?} contains slashes, whitespace or control characters",
+            bucket
+        ));
+    }
+    if !custom_endpoint {
+        if bucket.len() < 3 || bucket.len() > 63 {
+            return Err(anyhow!(
+                "Bucket name {:?} must be between 3 and 63 characters long",
+                bucket
+            ));
+        }
+        if !bucket
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '.' || c == '-')
+        {
+            return Err(anyhow!(
+                "Bucket name {:?} may only contain lowercase letters, digits, dots and hyphens",
+                bucket
+            ));
+        }
+        let alphanumeric = |c: Option<char>| c.map(|c| c.is_ascii_alphanumeric()).unwrap_or(false);
+        if !alphanumeric(bucket.chars().next()) || !alphanumeric(bucket.chars().last()) {
+            return Err(anyhow!(
+                "Bucket name {:?} must start and end with a letter or digit",
+                bucket
+            ));
+        }
+        if bucket.contains("..") {
+            return Err(anyhow!(
+                "Bucket name {:?} must not contain consecutive dots",
+                bucket
+            ));
+        }
+        if bucket.parse::<std::net::Ipv4Addr>().is_ok() {
+            return Err(anyhow!(
+                "Bucket name {:?} must not be formatted as an IP address",
+                bucket
+            ));
+        }
+    }
+
+    let key = s3_details.object_key.as_str();
+    if key.is_empty() {
+        return Err(anyhow!("Object key is empty"));
+    }
+    if key.len() > MAX_KEY_BYTES {
+        return Err(anyhow!(
+            "Object key is {} bytes long, at most {} are allowed",
+            key.len(),
+            MAX_KEY_BYTES
+        ));
+    }
+    if key.starts_with('/') {
+        return Err(anyhow!("Object key {:?} must not start with a slash", key));
+    }
+    if key.chars().any(char::is_control) {
+        return Err(anyhow!("Object key {:?} contains control characters", key));
+    }
+    Ok(())
+}
+
+/// Request-embedded credentials may only be sent to a custom endpoint, and not to one which is
+/// AWS after all, e.g. a request's `endpoint` of `https://s3.eu-west-2.amazonaws.com`.
+fn check_credentials_region(region: &Region) -> anyhow::Result<()> {
+    let endpoint = match region {
+        Region::Custom { endpoint, .. } => endpoint,
+        _ => {
+            return Err(anyhow!(
+                "credentials are only accepted with a custom endpoint"
+            ))
+        }
+    };
+    let with_scheme = if endpoint.contains("://") {
+        endpoint.clone()
+    } else {
+        format!("https://{}", endpoint)
+    };
+    let host = url::Url::parse(&with_scheme)
+        .ok()
+        .and_then(|url| url.host_str().map(str::to_ascii_lowercase))
+        .ok_or_else(|| anyhow!("Invalid endpoint {}", endpoint))?;
+    let host = host.trim_end_matches('.');
+    if ["amazonaws.com", "amazonaws.com.cn"]
+        .iter()
+        .any(|domain| host == *domain || host.ends_with(&format!(".{}", domain)))
+    {
+        return Err(anyhow!(
+            "credentials can't be sent to the AWS endpoint {}",
+            endpoint
+        ));
+    }
+    Ok(())
+}
+
+pub fn check_region(s3_details: &S3Details) -> anyhow::Result<()> {
+    let region = region(s3_details.region.as_deref(), s3_details.endpoint.as_deref())?;
+    s3_region(s3_details, &region).map(|_| ())
+}
+
+/// The region S3 itself is reached in, i.e. its dualstack endpoint with `useDualstack`. STS keeps
+/// using the plain region, and so do presigned URLs with `useAccelerate`, as only `client` knows
+/// how to reach the accelerate endpoint.
+fn s3_region(s3_details: &S3Details, region: &Region) -> anyhow::Result<Region> {
+    if s3_details.use_accelerate {
+        check_accelerate(s3_details, region)?;
+    }
+    if !s3_details.use_dualstack {
+        return Ok(region.clone());
+    }
+    if let Region::Custom { .. } = region {
+        return Err(anyhow!(
+            "useDualstack can't be combined with a custom endpoint"
+        ))
+        .code(ErrorCode::InvalidRequest);
+    }
+    let endpoint = format!(
+        "https://s3.dualstack.{}.{}",
+        region.name(),
+        aws_domain(region)
+    );
+    debug!("Using dualstack endpoint {}", endpoint);
+    Ok(Region::Custom {
+        name: region.name().to_owned(),
+        endpoint,
+    })
+}
+
+/// The domain of the region's partition.
+fn aws_domain(region: &Region) -> &'static str {
+    if region.name().starts_with("cn-") {
+        "amazonaws.com.cn"
+    } else {
+        "amazonaws.com"
+    }
+}
+
+/// Transfer Acceleration is only offered by AWS outside of China, and only to buckets whose names
+/// work as a subdomain.
+fn check_accelerate(s3_details: &S3Details, region: &Region) -> anyhow::Result<()> {
+    let problem = if let Region::Custom { .. } = region {
+        Some("useAccelerate can't be combined with a custom endpoint".to_owned())
+    } else if region.name().starts_with("cn-") {
+        Some(format!(
+            "S3 Transfer Acceleration isn't available in {}",
+            region.name()
+        ))
+    } else if s3_details.bucket.contains('.')
+        || !s3_details
+            .bucket
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-')
+    {
+        Some(format!(
+            "Bucket name {:?} can't be used with useAccelerate, which needs lowercase letters, digits and hyphens only",
+            s3_details.bucket
+        ))
+    } else {
+        None
+    };
+    match problem {
+        Some(problem) => Err(anyhow!(problem)).code(ErrorCode::InvalidRequest),
+        None => Ok(()),
+    }
+}
+
+/// The virtual-hosted accelerate endpoint of the bucket.
+fn accelerate_hostname(bucket: &str, dualstack: bool) -> String {
+    if dualstack {
+        format!("{}.s3-accelerate.dualstack.amazonaws.com", bucket)
+    } else {
+        format!("{}.s3-accelerate.amazonaws.com", bucket)
+    }
+}
+
+/// Sends requests to the accelerate endpoint. rusoto always puts the bucket into the path, which
+/// that endpoint doesn't accept, so the bucket is dropped from the path here and the request is
+/// signed afterwards, instead of by the client itself.
+struct AccelerateDispatcher<P, D> {
+    bucket: String,
+    credentials: Arc<P>,
+    inner: Arc<D>,
+}
+
+impl<P, D> DispatchSignedRequest for AccelerateDispatcher<P, D>
+where
+    P: ProvideAwsCredentials + Send + Sync + 'static,
+    D: DispatchSignedRequest + Send + Sync + 'static,
+{
+    fn dispatch(
+        &self,
+        mut request: SignedRequest,
+        timeout: Option<Duration>,
+    ) -> DispatchSignedRequestFuture {
+        let bucket_path = format!("/{}", self.bucket);
+        if request.path == bucket_path {
+            request.path = "/".to_owned();
+        } else if request.path.starts_with(&format!("{}/", bucket_path)) {
+            request.path = request.path[bucket_path.len()..].to_owned();
+        }
+        let credentials = Arc::clone(&self.credentials);
+        let inner = Arc::clone(&self.inner);
+        Box::pin(async move {
+            let credentials = credentials
+                .credentials()
+                .await
+                .map_err(|e| HttpDispatchError::new(e.to_string()))?;
+            request.sign(&credentials);
+            inner.dispatch(request, timeout).await
+        })
+    }
+}
+
+/// A client with `credentials`, which goes through the accelerate endpoint with `useAccelerate`.
+fn new_client<P>(credentials: P, s3_details: &S3Details, region: Region) -> anyhow::Result<S3Client>
+where
+    P: ProvideAwsCredentials + Send + Sync + 'static,
+{
+    if !s3_details.use_accelerate {
+        return Ok(S3Client::new_with(HttpClient::new()?, credentials, region));
+    }
+    let dispatcher = AccelerateDispatcher {
+        bucket: s3_details.bucket.clone(),
+        credentials: Arc::new(credentials),
+        inner: Arc::new(HttpClient::new()?),
+    };
+    Ok(accelerated_client(dispatcher, s3_details, &region))
+}
+
+fn accelerated_client<P, D>(
+    dispatcher: AccelerateDispatcher<P, D>,
+    s3_details: &S3Details,
+    region: &Region,
+) -> S3Client
+where
+    P: ProvideAwsCredentials + Send + Sync + 'static,
+    D: DispatchSignedRequest + Send + Sync + 'static,
+{
+    let endpoint = format!(
+        "https://{}",
+        accelerate_hostname(&s3_details.bucket, s3_details.use_dualstack)
+    );
+    debug!("Using accelerate endpoint {}", endpoint);
+    S3Client::new_with_client(
+        Client::new_not_signing(dispatcher),
+        Region::Custom {
+            name: region.name().to_owned(),
+            endpoint,
+        },
+    )
+}
+
+/// A request's own endpoint takes precedence over `S3_ENDPOINT`, and the request's region is used
+/// as the name of such custom regions. `S3_ENDPOINT` in turn takes precedence over the region.
+fn region(region: Option<&str>, endpoint: Option<&str>) -> anyhow::Result<Region> {
+    let env_endpoint = env::var("S3_ENDPOINT").ok();
+    if let Some(endpoint) = endpoint {
+        check_endpoint_allowed(
+            endpoint,
+            env_endpoint.as_deref(),
+            env::var("ALLOWED_S3_ENDPOINTS").ok().as_deref(),
+        )?;
+    }
+    region_with(region, endpoint, env_endpoint)
+}
+
+/// Requests are signed with the Lambda's own credentials, so a request's `endpoint` has to be
+/// `S3_ENDPOINT` itself or listed in the comma-separated `ALLOWED_S3_ENDPOINTS`. Anything else
+/// would hand signed requests and session tokens to whoever runs that endpoint.
+fn check_endpoint_allowed(
+    endpoint: &str,
+    env_endpoint: Option<&str>,
+    allowlist: Option<&str>,
+) -> anyhow::Result<()> {
+    let normalise = |endpoint: &str| endpoint.trim().trim_end_matches('/').to_ascii_lowercase();
+    let wanted = normalise(endpoint);
+    let allowed = env_endpoint
+        .into_iter()
+        .chain(allowlist.unwrap_or_default().split(','))
+        .map(normalise)
+        .any(|allowed| !allowed.is_empty() && allowed == wanted);
+    if !allowed {
+        return Err(anyhow!(
+            "endpoint {} isn't listed in ALLOWED_S3_ENDPOINTS",
+            endpoint
+        ))
+        .code(ErrorCode::InvalidRequest);
+    }
+    Ok(())
+}
+
+fn region_with(
+    region: Option<&str>,
+    endpoint: Option<&str>,
+    env_endpoint: Option<String>,
+) -> anyhow::Result<Region> {
+    let region = region.map(str::trim).filter(|region| !region.is_empty());
+    let region = if let Some(endpoint) = endpoint {
+        let region = Region::Custom {
+            name: region.unwrap_or("us-east-1").to_owned(),
+            endpoint: endpoint.to_owned(),
+        };
+        info!("Using non-standard endpoint {:?} from request", region);
+        region
+    } else if let Some(endpoint) = env_endpoint {
+        if let Some(region) = region {
+            info!("S3_ENDPOINT takes precedence over region {}", region);
+        }
+        let region = Region::Custom {
+            name: "us-east-1".to_owned(),
+            endpoint,
+        };
+        info!(
+            "Picked up non-standard endpoint {:?} from S3_ENDPOINT env var",
+            region
+        );
+        region
+    } else if let Some(region) = region {
+        Region::from_str(&region.to_lowercase())
+            .map_err(|_| {
+                anyhow!(
+                    "Invalid region {}, expected e.g. us-east-1, eu-west-2 or ap-southeast-2",
+                    region
+                )
+            })
+            .code(ErrorCode::InvalidRequest)?
+    } else {
+        default_region()?
+    };
+
+    Ok(region)
+}
+
+/// The region of requests which don't specify one: `DEFAULT_S3_REGION`, or else the Lambda's own
+/// region, or else ap-southeast-2.
+pub fn default_region() -> anyhow::Result<Region> {
+    for name in &["DEFAULT_S3_REGION", "AWS_REGION", "AWS_DEFAULT_REGION"] {
+        let value = match env::var(name) {
+            Ok(value) if !value.trim().is_empty() => value,
+            _ => continue,
+        };
+        let region = Region::from_str(&value.trim().to_lowercase())
+            .map_err(|_| anyhow!("Invalid {}: {}", name, value))?;
+        info!("Using region {} from {}", region.name(), name);
+        return Ok(region);
+    }
+    info!("No region configured, falling back to ap-southeast-2");
+    Ok(Region::ApSoutheast2)
+}
+
+/// Builds an RFC 6266 attachment header, with a plain ASCII `filename` for older clients and the
+/// exact name in `filename*`.
+fn content_disposition(filename: &str) -> String {
+    let mut fallback = String::new();
+    let mut encoded = String::new();
+    for c in filename.chars() {
+        match c {
+            '"' | '\\' => {
+                fallback.push('\\');
+                fallback.push(c);
+            }
+            c if c.is_ascii() && !c.is_ascii_control() => fallback.push(c),
+            _ => fallback.push('_'),
+        }
+        if c.is_ascii_alphanumeric() || "!#$&+-.^_`|~".contains(c) {
+            encoded.push(c);
+        } else {
+            let mut buf = [0; 4];
+            for byte in c.encode_utf8(&mut buf).bytes() {
+                encoded.push_str(&format!("%{:02X}", byte));
+            }
+        }
+    }
+    format!(
+        "attachment; filename=\"{}\"; filename*=UTF-8''{}",
+        fallback, encoded
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hyper::body::Bytes;
+    use hyper::{HeaderMap, StatusCode};
+    use rusoto_core::request::BufferedHttpResponse;
+    use serde_json::json;
+
+    /// The contents and content type of an object.
+    type StoredObject = (Vec<u8>, Option<String>);
+
+    /// Objects keyed by bucket and key, with a number of PUTs to fail with a 503 first.
+    #[derive(Default)]
+    struct MemoryStore {
+        objects: Mutex<HashMap<(String, String), StoredObject>>,
+        failures: Mutex<u32>,
+        puts: Mutex<u32>,
+    }
+
+    impl MemoryStore {
+        fn puts(&self) -> u32 {
+            *self.puts.lock().unwrap()
+        }
+    }
+
+    fn unknown(status: StatusCode) -> BufferedHttpResponse {
+        BufferedHttpResponse {
+            status,
+            body: Bytes::new(),
+            headers: HeaderMap::default(),
+        }
+    }
+
+    impl ObjectStore for MemoryStore {
+        fn put(
+            &self,
+            request: PutObjectRequest,
+        ) -> BoxFuture<'_, Result<PutObjectOutput, RusotoError<PutObjectError>>> {
+            Box::pin(async move {
+                *self.puts.lock().unwrap() += 1;
+                {
+                    let mut failures = self.failures.lock().unwrap();
+                    if *failures > 0 {
+                        *failures -= 1;
+                        return Err(RusotoError::Unknown(unknown(
+                            StatusCode::SERVICE_UNAVAILABLE,
+                        )));
+                    }
+                }
+                let mut body = Vec::new();
+                if let Some(stream) = request.body {
+                    stream
+                        .into_async_read()
+                        .read_to_end(&mut body)
+                        .await
+                        .unwrap();
+                }
+                self.objects
+                    .lock()
+                    .unwrap()
+                    .insert((request.bucket, request.key), (body, request.content_type));
+                Ok(PutObjectOutput {
+                    e_tag: Some("\"etag\"".to_owned()),
+                    ..Default::default()
+                })
+            })
+        }
+
+        fn head(
+            &self,
+            request: HeadObjectRequest,
+        ) -> BoxFuture<'_, Result<HeadObjectOutput, RusotoError<HeadObjectError>>> {
+            let exists = self
+                .objects
+                .lock()
+                .unwrap()
+                .contains_key(&(request.bucket, request.key));
+            Box::pin(future::ready(if exists {
+                Ok(HeadObjectOutput::default())
+            } else {
+                Err(RusotoError::Unknown(unknown(StatusCode::NOT_FOUND)))
+            }))
+        }
+    }
+
+    fn s3_details(extra: serde_json::Value) -> S3Details {
+        let mut details = json!({"bucket": "bucket", "objectKey": "report.pdf"});
+        if let (Some(details), Some(extra)) = (details.as_object_mut(), extra.as_object()) {
+            details.extend(extra.clone());
+        }
+        serde_json::from_value(details).unwrap()
+    }
+
+    fn key() -> (String, String) {
+        ("bucket".to_owned(), "report.pdf".to_owned())
+    }
+
+    #[test]
+    fn upload_to_puts_object() {
+        let store = MemoryStore::default();
+        let mut runtime = Runtime::new().unwrap();
+        let uploaded = upload_to(
+            &store,
+            &mut runtime,
+            b"%PDF-1.4".to_vec(),
+            &s3_details(json!({})),
+            "application/pdf",
+        )
+        .unwrap();
+        assert_eq!(uploaded.etag.as_deref(), Some("\"etag\""));
+        assert_eq!(
+            store.objects.lock().unwrap().get(&key()),
+            Some(&(b"%PDF-1.4".to_vec(), Some("application/pdf".to_owned())))
+        );
+        assert_eq!(store.puts(), 1);
+    }
+
+    #[test]
+    fn upload_to_retries_transient_errors() {
+        let store = MemoryStore::default();
+        *store.failures.lock().unwrap() = 1;
+        let mut runtime = Runtime::new().unwrap();
+        upload_to(
+            &store,
+            &mut runtime,
+            b"%PDF-1.4".to_vec(),
+            &s3_details(json!({})),
+            "application/pdf",
+        )
+        .unwrap();
+        assert_eq!(store.puts(), 2);
+        assert!(store.objects.lock().unwrap().contains_key(&key()));
+    }
+
+    #[test]
+    fn object_exists_in_reports_existing_objects() {
+        let store = MemoryStore::default();
+        let mut runtime = Runtime::new().unwrap();
+        let details = s3_details(json!({"skipIfExists": true}));
+        assert!(!object_exists_in(&store, &mut runtime, &details).unwrap());
+        store
+            .objects
+            .lock()
+            .unwrap()
+            .insert(key(), (Vec::new(), None));
+        assert!(object_exists_in(&store, &mut runtime, &details).unwrap());
+    }
+
+    #[test]
+    fn upload_to_refuses_to_overwrite_with_fail_if_exists() {
+        let store = MemoryStore::default();
+        store
+            .objects
+            .lock()
+            .unwrap()
+            .insert(key(), (Vec::new(), None));
+        let mut runtime = Runtime::new().unwrap();
+        let result = upload_to(
+            &store,
+            &mut runtime,
+            b"%PDF-1.4".to_vec(),
+            &s3_details(json!({"failIfExists": true})),
+            "application/pdf",
+        );
+        assert!(result.is_err());
+        assert_eq!(store.puts(), 0);
+    }
+
+    #[test]
+    fn region_is_trimmed_and_case_insensitive() {
+        assert_eq!(
+            region_with(Some(" ap-southeast-2 "), None, None).unwrap(),
+            Region::ApSoutheast2
+        );
+        assert_eq!(
+            region_with(Some("EU-WEST-2\n"), None, None).unwrap(),
+            Region::EuWest2
+        );
+    }
+
+    #[test]
+    fn invalid_region_is_an_invalid_request() {
+        let error = region_with(Some("ap-nowhere-1"), None, None).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "Invalid region ap-nowhere-1, expected e.g. us-east-1, eu-west-2 or ap-southeast-2"
+        );
+        assert_eq!(crate::error::error_code(&error), ErrorCode::InvalidRequest);
+    }
+
+    #[test]
+    fn endpoints_take_precedence_over_region() {
+        let env_endpoint = Some("http://localhost:4566".to_owned());
+        assert_eq!(
+            region_with(Some("eu-west-2"), None, env_endpoint.clone()).unwrap(),
+            Region::Custom {
+                name: "us-east-1".to_owned(),
+                endpoint: "http://localhost:4566".to_owned(),
+            }
+        );
+        assert_eq!(
+            region_with(Some("eu-west-2"), Some("http://minio:9000"), env_endpoint).unwrap(),
+            Region::Custom {
+                name: "eu-west-2".to_owned(),
+                endpoint: "http://minio:9000".to_owned(),
+            }
+        );
+    }
+
+    /// Fails every request, after recording the URL it would have been sent to.
+    #[derive(Clone, Default)]
+    struct RecordingDispatcher {
+        urls: std::sync::Arc<Mutex<Vec<String>>>,
+    }
+
+    impl rusoto_core::DispatchSignedRequest for RecordingDispatcher {
+        fn dispatch(
+            &self,
+            request: rusoto_core::signature::SignedRequest,
+            _timeout: Option<Duration>,
+        ) -> rusoto_core::request::DispatchSignedRequestFuture {
+            self.urls.lock().unwrap().push(format!(
+                "{}://{}{}",
+                request.scheme(),
+                request.hostname(),
+                request.path()
+            ));
+            Box::pin(future::err(rusoto_core::request::HttpDispatchError::new(
+                "recorded".to_owned(),
+            )))
+        }
+    }
+
+    #[test]
+    fn requests_use_path_style_addressing() {
+        let put_url = |region: Region| {
+            let dispatcher = RecordingDispatcher::default();
+            let client = S3Client::new_with(
+                dispatcher.clone(),
+                StaticProvider::new_minimal("key".to_owned(), "secret".to_owned()),
+                region,
+            );
+            let _ = Runtime::new()
+                .unwrap()
+                .block_on(client.put_object(PutObjectRequest {
+                    bucket: "my.bucket".to_owned(),
+                    key: "reports/a.pdf".to_owned(),
+                    ..Default::default()
+                }));
+            let urls = dispatcher.urls.lock().unwrap();
+            urls.last().cloned().unwrap()
+        };
+        assert_eq!(
+            put_url(region_with(None, Some("http://minio:9000"), None).unwrap()),
+            "http://minio:9000/my.bucket/reports/a.pdf"
+        );
+        assert_eq!(
+            put_url(Region::ApSoutheast2),
+            "https://s3.ap-southeast-2.amazonaws.com/my.bucket/reports/a.pdf"
+        );
+    }
+
+    #[test]
+    fn accelerate_needs_aws_and_subdomain_buckets() {
+        let details = s3_details(json!({"useAccelerate": true}));
+        assert_eq!(
+            s3_region(&details, &Region::EuWest2).unwrap(),
+            Region::EuWest2
+        );
+        let custom = region_with(None, Some("http://minio:9000"), None).unwrap();
+        for (details, region) in &[
+            (details.clone(), custom),
+            (details.clone(), Region::CnNorth1),
+            (
+                s3_details(json!({"useAccelerate": true, "bucket": "my.bucket"})),
+                Region::EuWest2,
+            ),
+        ] {
+            let error = s3_region(details, region).unwrap_err();
+            assert_eq!(crate::error::error_code(&error), ErrorCode::InvalidRequest);
+        }
+    }
+
+    #[test]
+    fn accelerated_requests_use_virtual_hosted_style() {
+        let put_url = |extra: serde_json::Value| {
+            let details = s3_details(extra);
+            let recorder = RecordingDispatcher::default();
+            let dispatcher = AccelerateDispatcher {
+                bucket: details.bucket.clone(),
+                credentials: Arc::new(StaticProvider::new_minimal(
+                    "key".to_owned(),
+                    "secret".to_owned(),
+                )),
+                inner: Arc::new(recorder.clone()),
+            };
+            let client = accelerated_client(dispatcher, &details, &Region::EuWest2);
+            let _ = Runtime::new()
+                .unwrap()
+                .block_on(client.put_object(PutObjectRequest {
+                    bucket: details.bucket.clone(),
+                    key: "reports/a.pdf".to_owned(),
+                    ..Default::default()
+                }));
+            let urls = recorder.urls.lock().unwrap();
+            urls.last().cloned().unwrap()
+        };
+        assert_eq!(
+            put_url(json!({"useAccelerate": true})),
+            "https://bucket.s3-accelerate.amazonaws.com/reports/a.pdf"
+        );
+        assert_eq!(
+            put_url(json!({"useAccelerate": true, "useDualstack": true})),
+            "https://bucket.s3-accelerate.dualstack.amazonaws.com/reports/a.pdf"
+        );
+    }
+
+    #[test]
+    fn dualstack_endpoint_matches_region() {
+        let details = s3_details(json!({"useDualstack": true}));
+        assert_eq!(
+            s3_region(&details, &Region::EuWest2).unwrap(),
+            Region::Custom {
+                name: "eu-west-2".to_owned(),
+                endpoint: "https://s3.dualstack.eu-west-2.amazonaws.com".to_owned(),
+            }
+        );
+        assert_eq!(
+            s3_region(&details, &Region::CnNorthwest1).unwrap(),
+            Region::Custom {
+                name: "cn-northwest-1".to_owned(),
+                endpoint: "https://s3.dualstack.cn-northwest-1.amazonaws.com.cn".to_owned(),
+            }
+        );
+        let custom = region_with(None, Some("http://minio:9000"), None).unwrap();
+        assert!(s3_region(&details, &custom).is_err());
+    }
+
+    #[test]
+    fn credentials_need_a_non_aws_endpoint() {
+        let custom = |endpoint: &str| Region::Custom {
+            name: "us-east-1".to_owned(),
+            endpoint: endpoint.to_owned(),
+        };
+        assert!(check_credentials_region(&custom("http://localhost:4566")).is_ok());
+        assert!(check_credentials_region(&custom("minio.internal:9000")).is_ok());
+        assert!(check_credentials_region(&custom("https://amazonaws.com.example.org")).is_ok());
+        for endpoint in &[
+            "https://s3.eu-west-2.amazonaws.com",
+            "s3.amazonaws.com",
+            "https://S3.AMAZONAWS.COM.",
+            "https://s3.cn-north-1.amazonaws.com.cn",
+            "https://s3.dualstack.us-east-1.amazonaws.com",
+        ] {
+            assert!(
+                check_credentials_region(&custom(endpoint)).is_err(),
+                "{} was accepted",
+                endpoint
+            );
+        }
+        assert!(check_credentials_region(&Region::EuWest2).is_err());
+    }
+
+    #[test]
+    fn request_endpoints_have_to_be_allowed() {
+        let allowlist = Some("http://minio:9000/, http://localhost:4566");
+        assert!(check_endpoint_allowed("http://minio:9000", None, allowlist).is_ok());
+        assert!(check_endpoint_allowed("HTTP://LOCALHOST:4566/", None, allowlist).is_ok());
+        assert!(check_endpoint_allowed("http://ceph:7480", Some("http://ceph:7480"), None).is_ok());
+        let error =
+            check_endpoint_allowed("https://attacker.example", None, allowlist).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "endpoint https://attacker.example isn't listed in ALLOWED_S3_ENDPOINTS"
+        );
+        assert_eq!(crate::error::error_code(&error), ErrorCode::InvalidRequest);
+        assert!(check_endpoint_allowed("http://minio:9000", None, None).is_err());
+        assert!(check_endpoint_allowed("", None, Some("")).is_err());
+    }
+
+    #[test]
+    fn escaped_keys_expand_to_themselves() {
+        for key in &["in/a{b.pdf", "in/{uuid}.pdf", "in/{{x}}}.pdf", "plain.pdf"] {
+            assert_eq!(
+                expand_key_template(&escape_key_template(key), "pdf").unwrap(),
+                *key
+            );
+        }
+        assert_eq!(
+            expand_key_template("a{{ext}.{ext}", "png").unwrap(),
+            "a{ext}.png"
+        );
+    }
+}
@@ -0,0 +1,80 @@
+use signal_hook::consts::SIGTERM;
+use signal_hook::iterator::Signals;
+use std::env;
+use std::fs;
+use std::io::{self, Write};
+use std::process;
+use std::thread;
+use std::time::Duration;
+
+use crate::renders;
+use crate::utils::temp_dir;
+#[allow(unused_imports)]
+use crate::{debug, error, info, warn};
+
+/// Lambda kills the runtime 500ms after SIGTERM, so this leaves some time for cleaning up.
+const DEFAULT_SHUTDOWN_GRACE_MS: u64 = 300;
+
+/// Cleans up on SIGTERM, which Lambda sends before shutting the container down. A render still
+/// running gets `SHUTDOWN_GRACE_MS` to finish first.
+pub fn install() -> io::Result<()> {
+    let mut signals = Signals::new([SIGTERM])?;
+    thread::spawn(move || {
+        if signals.forever().next().is_some() {
+            shutdown();
+        }
+    });
+    Ok(())
+}
+
+fn shutdown() {
+    info!("SIGTERM received, shutting down");
+    let grace = env::var("SHUTDOWN_GRACE_MS")
+        .ok()
+        .and_then(|grace| grace.parse::<u64>().ok())
+        .unwrap_or(DEFAULT_SHUTDOWN_GRACE_MS);
+    if !renders::wait_idle(Duration::from_millis(grace)) {
+        warn!(
+            "Render still running after {}ms, shutting down anyway",
+            grace
+        );
+    }
+    remove_temp_files();
+    let _ = io::stdout().flush();
+    let _ = io::stderr().flush();
+    process::exit(0);
+}
+
+/// Removes whatever temp files and directories of ours are left over in the temp directory.
+fn remove_temp_files() {
+    let entries = match fs::read_dir(temp_dir()) {
+        Ok(entries) => entries,
+        Err(e) => {
+            warn!("Failed to list {}: {}", temp_dir().display(), e);
+            return;
+        }
+    };
+    let mut removed = 0;
+    for entry in entries.filter_map(Result::ok) {
+        if !entry
+            .file_name()
+            .to_string_lossy()
+            .starts_with("wkhtmltopdf-")
+        {
+            continue;
+        }
+        let path = entry.path();
+        let result = if path.is_dir() {
+            fs::remove_dir_all(&path)
+        } else {
+            fs::remove_file(&path)
+        };
+        match result {
+            Ok(()) => removed += 1,
+            Err(e) => {
+                warn!("Failed to remove {}: {}", path.display(), e);
+            }
+        }
+    }
+    info!("Removed {} temp files", removed);
+}
@@ -0,0 +1,186 @@
+use anyhow::anyhow;
+use futures::future::BoxFuture;
+use rusoto_core::{Region, RusotoError};
+use rusoto_sns::{PublishError, PublishInput, PublishResponse, Sns, SnsClient};
+use serde_json::json;
+use std::env;
+use std::str::FromStr;
+use std::string::ToString;
+use tokio::runtime::Runtime;
+
+use crate::s3::default_region;
+use crate::utils::runtime;
+#[allow(unused_imports)]
+use crate::{debug, error, info, warn};
+use crate::{PdfRequest, PdfResponse};
+
+/// Publishes the outcome to NOTIFY_SNS_TOPIC_ARN when it's set. Failures are only logged, as the
+/// conversion itself is done either way.
+pub fn notify(ev: &PdfRequest, response: &PdfResponse) {
+    if let Ok(topic_arn) = env::var("NOTIFY_SNS_TOPIC_ARN") {
+        match publish(&topic_arn, ev, response) {
+            Ok(message_id) => {
+                info!("Published SNS message {} to {}", message_id, topic_arn);
+            }
+            Err(e) => {
+                warn!("Failed to publish to {}: {}", topic_arn, e);
+            }
+        }
+    }
+}
+
+/// Where notifications are published to. Apart from SNS itself, this allows capturing the messages
+/// `publish_to` builds.
+pub trait Publisher {
+    fn publish(
+        &self,
+        input: PublishInput,
+    ) -> BoxFuture<'_, Result<PublishResponse, RusotoError<PublishError>>>;
+}
+
+impl Publisher for SnsClient {
+    fn publish(
+        &self,
+        input: PublishInput,
+    ) -> BoxFuture<'_, Result<PublishResponse, RusotoError<PublishError>>> {
+        Sns::publish(self, input)
+    }
+}
+
+fn publish(topic_arn: &str, ev: &PdfRequest, response: &PdfResponse) -> anyhow::Result<String> {
+    let client = SnsClient::new(topic_region(topic_arn)?);
+    let mut runtime = runtime()?;
+    publish_to(&client, &mut runtime, topic_arn, ev, response)
+}
+
+fn publish_to(
+    publisher: &dyn Publisher,
+    runtime: &mut Runtime,
+    topic_arn: &str,
+    ev: &PdfRequest,
+    response: &PdfResponse,
+) -> anyhow::Result<String> {
+    let object_key = response
+        .object_key
+        .clone()
+        .or_else(|| ev.output().map(|output| output.object_key.clone()));
+    let message = json!({
+        "success": response.success,
+        "objectKey": object_key,
+        "errorCode": response.error_code,
+        "messages": response.messages,
+    });
+    let output = runtime
+        .block_on(publisher.publish(PublishInput {
+            topic_arn: Some(topic_arn.to_owned()),
+            message: message.to_string(),
+            ..Default::default()
+        }))
+        .map_err(|e| anyhow!(e.to_string()))?;
+    Ok(output.message_id.unwrap_or_default())
+}
+
+/// Topics are published to in their own region, which is part of the ARN, i.e.
+/// `arn:aws:sns:<region>:<account>:<name>`. `SNS_ENDPOINT` overrides it, like `S3_ENDPOINT`.
+fn topic_region(topic_arn: &str) -> anyhow::Result<Region> {
+    let name = topic_arn.split(':').nth(3).filter(|name| !name.is_empty());
+    if let Ok(endpoint) = env::var("SNS_ENDPOINT") {
+        return Ok(Region::Custom {
+            name: name.unwrap_or("us-east-1").to_owned(),
+            endpoint,
+        });
+    }
+    match name {
+        Some(name) => Region::from_str(name)
+            .map_err(|_| anyhow!("Invalid region {} in NOTIFY_SNS_TOPIC_ARN", name)),
+        None => default_region(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ErrorCode;
+    use rusoto_core::request::BufferedHttpResponse;
+    use serde_json::Value;
+    use std::sync::Mutex;
+
+    const TOPIC_ARN: &str = "arn:aws:sns:ap-southeast-2:123456789012:pdfs";
+
+    /// Keeps every published message, or fails every call when `fail` is set.
+    #[derive(Default)]
+    struct MemoryPublisher {
+        inputs: Mutex<Vec<PublishInput>>,
+        fail: bool,
+    }
+
+    impl Publisher for MemoryPublisher {
+        fn publish(
+            &self,
+            input: PublishInput,
+        ) -> BoxFuture<'_, Result<PublishResponse, RusotoError<PublishError>>> {
+            Box::pin(async move {
+                if self.fail {
+                    return Err(RusotoError::Unknown(BufferedHttpResponse {
+                        status: hyper::StatusCode::INTERNAL_SERVER_ERROR,
+                        body: hyper::body::Bytes::new(),
+                        headers: Default::default(),
+                    }));
+                }
+                self.inputs.lock().unwrap().push(input);
+                Ok(PublishResponse {
+                    message_id: Some("message-1".to_owned()),
+                })
+            })
+        }
+    }
+
+    fn request() -> PdfRequest {
+        serde_json::from_value(json!({
+            "pages": [],
+            "output": {"bucket": "bucket", "objectKey": "reports/a.pdf"}
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn publish_to_sends_the_outcome_to_the_topic() {
+        let publisher = MemoryPublisher::default();
+        let response = PdfResponse {
+            success: false,
+            messages: vec!["wkhtmltopdf failed".to_owned()],
+            error_code: Some(ErrorCode::RenderFailed),
+            ..Default::default()
+        };
+        let mut runtime = Runtime::new().unwrap();
+        let message_id =
+            publish_to(&publisher, &mut runtime, TOPIC_ARN, &request(), &response).unwrap();
+        assert_eq!(message_id, "message-1");
+
+        let inputs = publisher.inputs.lock().unwrap();
+        assert_eq!(inputs.len(), 1);
+        assert_eq!(inputs[0].topic_arn.as_deref(), Some(TOPIC_ARN));
+        let message: Value = serde_json::from_str(&inputs[0].message).unwrap();
+        assert_eq!(
+            message,
+            json!({
+                "success": false,
+                "objectKey": "reports/a.pdf",
+                "errorCode": ErrorCode::RenderFailed,
+                "messages": ["wkhtmltopdf failed"],
+            })
+        );
+    }
+
+    #[test]
+    fn publish_to_reports_failures() {
+        let publisher = MemoryPublisher {
+            fail: true,
+            ..Default::default()
+        };
+        let mut runtime = Runtime::new().unwrap();
+        let response = PdfResponse::default();
+        assert!(publish_to(&publisher, &mut runtime, TOPIC_ARN, &request(), &response).is_err());
+        assert!(publisher.inputs.lock().unwrap().is_empty());
+    }
+}
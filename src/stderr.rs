@@ -0,0 +1,121 @@
+use crate::{PageSummary, PageType, PdfPage, Progress};
+
+/// Substrings of the non-fatal problems wkhtmltopdf reports, matched case-insensitively since the
+/// exact wording differs between versions.
+const WARNING_PATTERNS: &[&str] = &[
+    "warning",
+    "failed to load",
+    "network error",
+    "exit with code",
+    "error:",
+];
+
+/// Splits wkhtmltopdf's stderr into lines, including the carriage-return-separated progress
+/// updates.
+pub fn lines(stderr: &str) -> impl Iterator<Item = &str> {
+    stderr
+        .split(['\n', '\r'])
+        .map(str::trim)
+        .filter(|line| !line.is_empty())
+}
+
+pub fn warnings(stderr: &str) -> Vec<String> {
+    let mut warnings = Vec::new();
+    for line in lines(stderr) {
+        let lowercase = line.to_lowercase();
+        if WARNING_PATTERNS
+            .iter()
+            .any(|pattern| lowercase.contains(pattern))
+            && !warnings.iter().any(|warning| warning == line)
+        {
+            warnings.push(line.to_owned());
+        }
+    }
+    warnings
+}
+
+/// The last phase and percentage wkhtmltopdf reported, e.g. `Loading pages (1/6)` followed by
+/// `[======>      ] 10%`.
+pub fn progress(stderr: &str) -> Option<Progress> {
+    let mut progress: Option<Progress> = None;
+    for line in lines(stderr) {
+        if let Some(bar) = line.strip_prefix('[') {
+            let percent = bar
+                .split_once(']')
+                .and_then(|(_, status)| status.trim().strip_suffix('%'))
+                .and_then(|percent| percent.trim().parse().ok());
+            if let (Some(progress), Some(percent)) = (progress.as_mut(), percent) {
+                progress.percent = Some(percent);
+            }
+        } else if let Some((phase, steps)) = line
+            .strip_suffix(')')
+            .and_then(|line| line.rsplit_once(" ("))
+        {
+            if let Some((step, steps)) = steps.split_once('/') {
+                if let (Ok(step), Ok(steps)) = (step.parse(), steps.parse()) {
+                    progress = Some(Progress {
+                        phase: phase.to_owned(),
+                        step,
+                        steps,
+                        percent: None,
+                    });
+                }
+            }
+        }
+    }
+    progress
+}
+
+/// Attributes the load errors in stderr to pages, as far as possible. Pages from URLs are matched
+/// by their URL, whereas local pages all look the same and are only matched if there's just one.
+pub fn page_summaries(pages: &[&PdfPage], stderr: &str, success: bool) -> Vec<PageSummary> {
+    let failures = lines(stderr)
+        .filter(|line| line.to_lowercase().contains("failed to load"))
+        .collect::<Vec<_>>();
+    let local_pages = pages
+        .iter()
+        .filter(|page| page.page_type != PageType::TOC && page.html_url.is_none())
+        .count();
+    let failed = |page: &PdfPage, line: &str| match &page.html_url {
+        // wkhtmltopdf normalises e.g. https://example.com to https://example.com/
+        Some(url) => line.contains(url.trim_end_matches('/')),
+        None => local_pages == 1 && line.contains("wkhtmltopdf-input"),
+    };
+    let attributed = failures.iter().all(|line| {
+        pages
+            .iter()
+            .any(|page| page.page_type != PageType::TOC && failed(page, line))
+    });
+
+    pages
+        .iter()
+        .enumerate()
+        .map(|(index, page)| {
+            let source = if page.page_type == PageType::TOC {
+                "toc"
+            } else if page.html_url.is_some() {
+                "url"
+            } else if page.html_s3.is_some() {
+                "s3"
+            } else if page.markdown_base64.is_some() {
+                "markdown"
+            } else {
+                "base64"
+            };
+            let load_status = if page.page_type != PageType::TOC
+                && failures.iter().any(|line| failed(page, line))
+            {
+                "failed"
+            } else if success && attributed {
+                "ok"
+            } else {
+                "unknown"
+            };
+            PageSummary {
+                index,
+                source,
+                load_status,
+            }
+        })
+        .collect()
+}
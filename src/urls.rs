@@ -0,0 +1,180 @@
+use anyhow::anyhow;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, ToSocketAddrs};
+use url::{Host, Url};
+
+/// Rejects URLs which aren't plain http(s), and unless `allow_private` is set, URLs whose host
+/// resolves to a loopback, link-local, private or shared address, e.g. the instance metadata
+/// endpoint. This only checks the URL itself, at the time it's validated: redirects and the
+/// subresources wkhtmltopdf fetches aren't checked again, and neither is the address the host
+/// resolves to by then.
+pub fn validate_url(url: &str, allow_private: bool) -> anyhow::Result<()> {
+    let parsed = Url::parse(url).map_err(|e| anyhow!("Invalid URL {}: {}", url, e.to_string()))?;
+    if parsed.scheme() != "http" && parsed.scheme() != "https" {
+        return Err(anyhow!(
+            "Unsupported URL scheme {} in {}, only http and https are allowed",
+            parsed.scheme(),
+            url
+        ));
+    }
+    if allow_private {
+        return Ok(());
+    }
+
+    let addresses = match parsed.host() {
+        Some(Host::Ipv4(address)) => vec![IpAddr::V4(address)],
+        Some(Host::Ipv6(address)) => vec![IpAddr::V6(address)],
+        Some(Host::Domain(domain)) => {
+            let port = parsed.port_or_known_default().unwrap_or(80);
+            (domain, port)
+                .to_socket_addrs()
+                .map_err(|e| anyhow!("Failed to resolve host of {}: {}", url, e.to_string()))?
+                .map(|address| address.ip())
+                .collect()
+        }
+        None => return Err(anyhow!("No host in URL {}", url)),
+    };
+    for address in addresses {
+        if is_private(&address) {
+            return Err(anyhow!(
+                "URL {} resolves to non-public address {}",
+                url,
+                address
+            ));
+        }
+    }
+
+    Ok(())
+}
+
+fn is_private(address: &IpAddr) -> bool {
+    match address {
+        IpAddr::V4(address) => is_private_v4(address),
+        IpAddr::V6(address) => match embedded_v4(address) {
+            Some(embedded) => is_private_v4(&embedded) || is_private_v6(address),
+            None => is_private_v6(address),
+        },
+    }
+}
+
+/// The IPv4 address within IPv4-mapped (`::ffff:a.b.c.d`), NAT64 (`64:ff9b::a.b.c.d`) and
+/// deprecated IPv4-compatible (`::a.b.c.d`) addresses, which all reach that IPv4 host.
+fn embedded_v4(address: &Ipv6Addr) -> Option<Ipv4Addr> {
+    if let Some(mapped) = address.to_ipv4_mapped() {
+        return Some(mapped);
+    }
+    let segments = address.segments();
+    let low = Ipv4Addr::new(
+        (segments[6] >> 8) as u8,
+        segments[6] as u8,
+        (segments[7] >> 8) as u8,
+        segments[7] as u8,
+    );
+    match segments[..6] {
+        [0x64, 0xff9b, 0, 0, 0, 0] | [0, 0, 0, 0, 0, 0] => Some(low),
+        _ => None,
+    }
+}
+
+fn is_private_v4(address: &Ipv4Addr) -> bool {
+    let octets = address.octets();
+    address.is_loopback()
+        || address.is_link_local()
+        || address.is_private()
+        || address.is_broadcast()
+        // 0.0.0.0/8 "this network"
+        || octets[0] == 0
+        // 100.64.0.0/10 shared address space, e.g. carrier-grade NAT
+        || (octets[0] == 100 && octets[1] & 0xc0 == 64)
+}
+
+fn is_private_v6(address: &Ipv6Addr) -> bool {
+    let first_segment = address.segments()[0];
+    address.is_loopback()
+        || address.is_unspecified()
+        // fe80::/10 link-local
+        || first_segment & 0xffc0 == 0xfe80
+        // fc00::/7 unique local
+        || first_segment & 0xfe00 == 0xfc00
+}
+
+/// Checks a `[scheme://][user:password@]host:port` proxy, where the scheme is http (default) or
+/// socks5. Returns the proxy without its credentials, for logging.
+pub fn validate_proxy(proxy: &str) -> anyhow::Result<String> {
+    let with_scheme = if proxy.contains("://") {
+        proxy.to_owned()
+    } else {
+        format!("http://{}", proxy)
+    };
+    let mut parsed = Url::parse(&with_scheme).map_err(|e| {
+        anyhow!(
+            "Invalid proxy, expected [scheme://][user:password@]host:port: {}",
+            e.to_string()
+        )
+    })?;
+    if parsed.scheme() != "http" && parsed.scheme() != "socks5" {
+        return Err(anyhow!(
+            "Unsupported proxy scheme {}, only http and socks5 are allowed",
+            parsed.scheme()
+        ));
+    }
+    if parsed.host_str().map(str::is_empty).unwrap_or(true) {
+        return Err(anyhow!("Proxy has no host"));
+    }
+    if parsed.port_or_known_default().is_none() {
+        return Err(anyhow!("Proxy has no port"));
+    }
+    if parsed.path() != "/" && !parsed.path().is_empty()
+        || parsed.query().is_some()
+        || parsed.fragment().is_some()
+    {
+        return Err(anyhow!("Proxy must not have a path, query or fragment"));
+    }
+    // neither can fail for URLs with a host
+    let _ = parsed.set_username("");
+    let _ = parsed.set_password(None);
+    Ok(parsed.as_str().trim_end_matches('/').to_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn private_v4_addresses_are_rejected() {
+        for url in &[
+            "http://127.0.0.1/",
+            "http://169.254.169.254/latest/meta-data/",
+            "http://10.1.2.3/",
+            "http://172.16.0.1/",
+            "http://192.168.1.1/",
+            "http://0.0.0.0/",
+            "http://0.1.2.3/",
+            "http://100.64.0.1/",
+            "http://100.127.255.254/",
+            "http://255.255.255.255/",
+            "http://[::ffff:100.64.0.1]/",
+            "http://[::a9fe:a9fe]/",
+            "http://[::127.0.0.1]/",
+            "http://[64:ff9b::a9fe:a9fe]/",
+            "http://[64:ff9b::10.0.0.1]/",
+            "http://[::1]/",
+            "http://[::]/",
+        ] {
+            assert!(validate_url(url, false).is_err(), "{} was allowed", url);
+            assert!(validate_url(url, true).is_ok(), "{} was rejected", url);
+        }
+    }
+
+    #[test]
+    fn public_addresses_are_allowed() {
+        for url in &[
+            "https://93.184.216.34/",
+            "http://100.63.255.255/",
+            "http://100.128.0.1/",
+            "http://[64:ff9b::5db8:d822]/",
+            "https://[2606:2800:220:1:248:1893:25c8:1946]/",
+        ] {
+            assert!(validate_url(url, false).is_ok(), "{} was rejected", url);
+        }
+    }
+}
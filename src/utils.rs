@@ -1,28 +1,90 @@
+use anyhow::anyhow;
+use slog::Level;
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::MutexGuard;
+use tokio::runtime::Runtime;
+
 #[macro_export]
 macro_rules! error {
     ($($args:tt)+) => {
-        #[cfg(not(test))] slog::log!(crate::LOGGER.get().unwrap(), slog::Level::Error, "", $($args)+);
+        #[cfg(not(test))] slog::log!($crate::LOGGER.get().unwrap(), slog::Level::Error, "", $($args)+);
         #[cfg(test)] eprintln!("{}: {}", slog::Level::Error, format!($($args)+));
     };
 }
 #[macro_export]
 macro_rules! warn {
     ($($args:tt)+) => {
-        #[cfg(not(test))] slog::log!(crate::LOGGER.get().unwrap(), slog::Level::Warning, "", $($args)+);
+        #[cfg(not(test))] slog::log!($crate::LOGGER.get().unwrap(), slog::Level::Warning, "", $($args)+);
         #[cfg(test)] eprintln!("{}: {}", slog::Level::Warning, format!($($args)+));
     };
 }
 #[macro_export]
 macro_rules! info {
     ($($args:tt)+) => {
-        #[cfg(not(test))] slog::log!(crate::LOGGER.get().unwrap(), slog::Level::Info, "", $($args)+);
+        #[cfg(not(test))] slog::log!($crate::LOGGER.get().unwrap(), slog::Level::Info, "", $($args)+);
         #[cfg(test)] eprintln!("{}: {}", slog::Level::Info, format!($($args)+));
     };
 }
 #[macro_export]
 macro_rules! debug {
     ($($args:tt)+) => {
-        #[cfg(not(test))] slog::log!(crate::LOGGER.get().unwrap(), slog::Level::Debug, "", $($args)+);
+        #[cfg(not(test))] slog::log!($crate::LOGGER.get().unwrap(), slog::Level::Debug, "", $($args)+);
         #[cfg(test)] eprintln!("{}: {}", slog::Level::Debug, format!($($args)+));
     };
 }
+
+/// The Tokio runtime shared by every invocation of a warm container.
+pub fn runtime() -> anyhow::Result<MutexGuard<'static, Runtime>> {
+    crate::RUNTIME
+        .get()
+        .ok_or_else(|| anyhow!("Tokio runtime is not initialised"))?
+        .lock()
+        .map_err(|_| anyhow!("Failed to lock Tokio runtime"))
+}
+
+/// Reads `LOG_LEVEL`, `info` by default. Errors are always logged, so `critical` is treated as
+/// `error`.
+pub fn log_level() -> anyhow::Result<Level> {
+    let level = match env::var("LOG_LEVEL") {
+        Ok(level) if level.trim().eq_ignore_ascii_case("warning") => Level::Warning,
+        Ok(level) => {
+            Level::from_str(level.trim()).map_err(|_| anyhow!("Invalid LOG_LEVEL: {}", level))?
+        }
+        Err(_) => Level::Info,
+    };
+    Ok(if level == Level::Critical {
+        Level::Error
+    } else {
+        level
+    })
+}
+
+/// Picks `WKHTMLTOPDF_TMPDIR`, or the system temp directory which honours `TMPDIR`, and makes sure
+/// it's writable.
+pub fn resolve_temp_dir() -> anyhow::Result<PathBuf> {
+    let dir = match env::var("WKHTMLTOPDF_TMPDIR") {
+        Ok(dir) => PathBuf::from(dir),
+        Err(_) => env::temp_dir(),
+    };
+    let dir = fs::canonicalize(&dir)
+        .map_err(|e| anyhow!("Temp directory {} does not exist: {}", dir.display(), e))?;
+    if !dir.is_dir() {
+        return Err(anyhow!(
+            "Temp directory {} is not a directory",
+            dir.display()
+        ));
+    }
+    tempfile::Builder::new()
+        .prefix(".wkhtmltopdf-probe")
+        .tempfile_in(&dir)
+        .map_err(|e| anyhow!("Temp directory {} is not writable: {}", dir.display(), e))?;
+    Ok(dir)
+}
+
+/// Where every temp file and directory is created, see `resolve_temp_dir`.
+pub fn temp_dir() -> PathBuf {
+    crate::TEMP_DIR.get().cloned().unwrap_or_else(env::temp_dir)
+}
@@ -1,193 +1,2299 @@
 use anyhow::anyhow;
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
 use lambda_runtime::error::HandlerError;
-use rusoto_core::Region;
-use rusoto_s3::{PutObjectOutput, PutObjectRequest, S3Client, S3};
 use std::env;
-use std::io::{Read, Write};
-use std::path::Path;
-use std::process::{Command, Stdio};
-use std::str::FromStr;
+use std::fs;
+use std::io::{self, Read, Write};
+use std::path::{Component, Path, PathBuf};
+use std::process::{Child, ChildStdout, Command, ExitStatus, Stdio};
 use std::string::ToString;
-use tempfile::{Builder, NamedTempFile};
+use std::thread;
+use std::time::{Duration, Instant};
+use tempfile::{Builder, NamedTempFile, TempDir};
 
+use crate::apigateway;
+use crate::callback;
+use crate::config;
+use crate::error::{error_code, WithErrorCode};
+use crate::events;
+use crate::fonts;
+use crate::ghostscript;
+use crate::html::{self, markdown_to_html};
+use crate::installation::Installation;
+use crate::local;
+use crate::logging;
+use crate::metrics::{self, millis, Metrics};
+use crate::options::{
+    allowlist, check_allowed, check_values, dedupe, merge_defaults, redact, redact_output,
+    without_overridden,
+};
+use crate::pdf::{self, HashingReader, OutputStats};
+use crate::qpdf;
+use crate::renders;
+use crate::s3::{
+    check_content_type, check_expires, check_location, check_presign_seconds, check_region,
+    download_all, expand_key_template, object_exists, presign, storage_class, stream_upload,
+    tagging, upload, PendingUpload,
+};
+use crate::sns;
+use crate::stderr;
+use crate::urls::{validate_proxy, validate_url};
+use crate::utils::temp_dir;
+use crate::xray::Subsegment;
 #[allow(unused_imports)]
 use crate::{debug, error, info, warn};
-use crate::{PageType, PdfRequest, PdfResponse, S3Details};
+use crate::{
+    BatchRequest, BatchResponse, Destination, ErrorCode, HealthResponse, HtmlEncoding, InlineImage,
+    OutputMode, PageErrorPolicy, PageType, PdfOption, PdfPage, PdfRequest, PdfResponse, S3Details,
+};
 
-const WKHTMLTOPDF_LAYER_PATH: &'static str = "/opt/bin/wkhtmltopdf";
-const WKHTMLTOPDF_BUNDLED_PATH: &'static str = "/bin/wkhtmltopdf";
+// Lambda caps synchronous responses at 6MB, leave some room for the rest of the JSON
+const DEFAULT_INLINE_MAX_BYTES: usize = 6 * 1024 * 1024 - 16 * 1024;
+const DEFAULT_MAX_HTML_BYTES: usize = 5 * 1024 * 1024;
+const DEFAULT_TIMEOUT_MARGIN_MS: u64 = 2000;
+const MIN_ZOOM: f64 = 0.1;
+const MAX_ZOOM: f64 = 5.0;
+const MIN_DPI: u32 = 50;
+const MAX_DPI: u32 = 1200;
+const MAX_HEADER_SPACING: f64 = 100.0;
+const MAX_HEADER_FONT_SIZE: u32 = 72;
 
-pub fn convert(ev: PdfRequest, _ctx: lambda_runtime::Context) -> Result<PdfResponse, HandlerError> {
-    let response = convert_inner(&ev, &_ctx);
-    match response {
-        Ok(response) => Ok(response),
-        Err(e) => Ok(PdfResponse {
+/// Unwraps API Gateway proxy events, whose responses are wrapped in turn.
+pub fn handle(
+    ev: serde_json::Value,
+    ctx: lambda_runtime::Context,
+) -> Result<serde_json::Value, HandlerError> {
+    logging::set_request_id(&ctx.aws_request_id);
+    if !apigateway::is_proxy_event(&ev) {
+        return dispatch(ev, ctx);
+    }
+    info!("API Gateway proxy event");
+    // errors become responses, since API Gateway would only report them as a bare 502
+    let response = match apigateway::request(&ev)
+        .and_then(|ev| dispatch(ev, ctx).map_err(|e| anyhow!(e.to_string())))
+    {
+        Ok(response) => response,
+        Err(e) => serde_json::to_value(PdfResponse {
             success: false,
             messages: vec![e.to_string()],
-        }),
+            error_code: Some(ErrorCode::InvalidRequest),
+            ..Default::default()
+        })?,
+    };
+    apigateway::response(&response).map_err(|e| HandlerError::from(e.to_string().as_str()))
+}
+
+/// Dispatches between batches, i.e. events with `documents`, S3 notifications and single
+/// documents.
+fn dispatch(
+    ev: serde_json::Value,
+    ctx: lambda_runtime::Context,
+) -> Result<serde_json::Value, HandlerError> {
+    if ev.get("warmup").and_then(serde_json::Value::as_bool) == Some(true) {
+        return Ok(serde_json::to_value(health())?);
+    }
+    if events::is_s3_event(&ev) {
+        return Ok(serde_json::to_value(convert_s3_event(ev, ctx)?)?);
+    }
+    if ev.get("documents").is_none() {
+        return Ok(serde_json::to_value(convert(
+            serde_json::from_value(ev)?,
+            ctx,
+        )?)?);
+    }
+
+    let batch: BatchRequest = serde_json::from_value(ev)?;
+    info!("Converting batch of {} documents", batch.documents.len());
+    let mut results = Vec::new();
+    for (i, document) in batch.documents.into_iter().enumerate() {
+        info!("Converting batch document {}", i);
+        // a malformed document only fails itself, not the whole batch
+        let result = match serde_json::from_value(document) {
+            Ok(document) => convert(document, ctx.clone())?,
+            Err(e) => PdfResponse {
+                success: false,
+                messages: vec![format!("Invalid document: {}", e)],
+                error_code: Some(ErrorCode::InvalidRequest),
+                ..Default::default()
+            },
+        };
+        results.push(result);
+    }
+    Ok(serde_json::to_value(BatchResponse { results })?)
+}
+
+fn convert_s3_event(
+    ev: serde_json::Value,
+    ctx: lambda_runtime::Context,
+) -> Result<BatchResponse, HandlerError> {
+    let requests = events::requests(ev).map_err(|e| HandlerError::from(e.to_string().as_str()))?;
+    info!("Converting {} S3 event records", requests.len());
+    let mut results = Vec::new();
+    for request in requests {
+        let result = match request {
+            Ok(Some(request)) => convert(request, ctx.clone())?,
+            Ok(None) => PdfResponse {
+                success: true,
+                skipped: true,
+                messages: vec!["Not an HTML object".to_owned()],
+                ..Default::default()
+            },
+            Err(e) => PdfResponse {
+                success: false,
+                messages: vec![e.to_string()],
+                error_code: Some(ErrorCode::InvalidRequest),
+                ..Default::default()
+            },
+        };
+        results.push(result);
     }
+    Ok(BatchResponse { results })
 }
 
-fn convert_inner(ev: &PdfRequest, _ctx: &lambda_runtime::Context) -> anyhow::Result<PdfResponse> {
-    info!("Converting {} pages", ev.pages.len());
-    info!(
-        "PDF will be uploaded to s3://{}/{}",
-        ev.output.bucket, ev.output.object_key
+fn health() -> HealthResponse {
+    info!("Warmup event, checking installation");
+    let installation = match Installation::resolve() {
+        Ok(installation) => installation,
+        Err(e) => {
+            error!("{}", e);
+            return HealthResponse {
+                success: false,
+                messages: vec![e.to_string()],
+                ..Default::default()
+            };
+        }
+    };
+    let wkhtmltopdf_version = installation.version();
+    HealthResponse {
+        success: wkhtmltopdf_version.is_some(),
+        messages: match wkhtmltopdf_version {
+            Some(_) => Vec::new(),
+            None => vec!["wkhtmltopdf --version failed".to_owned()],
+        },
+        wkhtmltopdf_version,
+        wkhtmltopdf_path: Some(installation.wkhtmltopdf_path),
+        fontconfig_path: Some(installation.fontconfig_path),
+    }
+}
+
+pub fn convert(
+    mut ev: PdfRequest,
+    ctx: lambda_runtime::Context,
+) -> Result<PdfResponse, HandlerError> {
+    let mut metrics = Metrics::default();
+    config::apply(&mut ev);
+    if ev.validate_only {
+        return Ok(validate(&mut ev));
+    }
+    let response = resolve_object_key(&mut ev)
+        .code(ErrorCode::InvalidRequest)
+        .and_then(|_| convert_inner(&ev, &ctx, &mut metrics));
+    metrics::emit(
+        &metrics,
+        response.as_ref().map(|r| r.success).unwrap_or(false),
     );
+    let response = match response {
+        Ok(response) => response,
+        Err(e) => PdfResponse {
+            success: false,
+            messages: vec![e.to_string()],
+            error_code: Some(error_code(&e)),
+            ..Default::default()
+        },
+    };
+    callback::notify(&ev, &response);
+    sns::notify(&ev, &response);
+    Ok(response)
+}
 
-    let (mut args, _files) = build_args(&ev)?;
-    let mut file = Builder::new()
-        .prefix("wkhtmltopdf-output")
-        .suffix(".pdf")
-        .tempfile()
-        .map_err(|e| anyhow!("Failed to create temp file: {}", e.to_string()))?;
-    args.push(file.path().to_string_lossy().to_string());
-    info!("Args: {:?}", args);
-
-    let (wkhtmltopdf_path, fontconfig_path) = if Path::new(WKHTMLTOPDF_LAYER_PATH).exists() {
-        (WKHTMLTOPDF_LAYER_PATH.to_owned(), "/opt/fonts".to_owned())
-    } else if env::var("LAMBDA_TASK_ROOT").is_ok()
-        && Path::new(
-            (env::var("LAMBDA_TASK_ROOT").unwrap().to_string() + WKHTMLTOPDF_BUNDLED_PATH).as_str(),
-        )
-        .exists()
+/// Expands the object key template once, so that every later step sees the final key.
+pub fn resolve_object_key(ev: &mut PdfRequest) -> anyhow::Result<()> {
+    let extension = ev.output_format.extension();
+    for output in &mut ev.outputs {
+        let object_key = expand_key_template(&output.object_key, extension)?;
+        if object_key != output.object_key {
+            info!(
+                "Expanded object key {} to {}",
+                output.object_key, object_key
+            );
+            output.object_key = object_key;
+        }
+    }
+    Ok(())
+}
+
+fn resolve_local_output(ev: &PdfRequest) -> anyhow::Result<Option<PathBuf>> {
+    match &ev.local_output {
+        Some(_) if !ev.outputs.is_empty() => {
+            Err(anyhow!("output and localOutput are mutually exclusive"))
+        }
+        Some(local_output) => local::resolve(&local_output.path).map(Some),
+        None => Ok(None),
+    }
+}
+
+fn convert_inner(
+    ev: &PdfRequest,
+    ctx: &lambda_runtime::Context,
+    metrics: &mut Metrics,
+) -> anyhow::Result<PdfResponse> {
+    let page_count = ev.pages.len() + ev.groups.iter().map(|g| g.pages.len()).sum::<usize>();
+    info!("Converting {} pages", page_count);
+    let local_output = resolve_local_output(ev).code(ErrorCode::InvalidRequest)?;
+    if let Some(callback_url) = &ev.callback_url {
+        validate_url(callback_url, ev.allow_private_urls).code(ErrorCode::InvalidRequest)?;
+    }
+    if let Some(problem) = output_problems(ev).into_iter().next() {
+        return Err(problem).code(ErrorCode::InvalidRequest);
+    }
+    if !ev.outputs.is_empty() {
+        for output in &ev.outputs {
+            info!(
+                "PDF will be uploaded to s3://{}/{}",
+                output.bucket, output.object_key
+            );
+        }
+    } else if let Some(local_output) = &local_output {
+        info!("PDF will be written to {}", local_output.display());
+    } else {
+        info!("PDF will be returned inline");
+    }
+
+    let mut files = TempFiles::new(ev.dry_run);
+    let groups = build_group_args(ev, &mut files).code(ErrorCode::InvalidRequest)?;
+    let mut args = if groups.is_empty() {
+        build_args(ev, &mut files).code(ErrorCode::InvalidRequest)?
+    } else {
+        // the dry run previews the commands of all groups one after another
+        groups.iter().flat_map(|group| group.args.clone()).collect()
+    };
+    if let Some(problem) = combination_problems(ev).into_iter().next() {
+        return Err(problem).code(ErrorCode::InvalidRequest);
+    }
+    let stream_output = ev.output().filter(|output| output.stream_upload);
+    let also_inline = ev.output_mode == Some(OutputMode::UploadAndInline);
+    let page_range = ev
+        .page_range
+        .as_deref()
+        .map(qpdf::parse_page_range)
+        .transpose()
+        .code(ErrorCode::InvalidRequest)?;
+    let output_path = if stream_output.is_some() {
+        args.push("-".to_owned());
+        None
+    } else {
+        let output_path = files.write(
+            "wkhtmltopdf-output",
+            &format!(".{}", ev.output_format.extension()),
+            &[],
+        )?;
+        if groups.is_empty() {
+            args.push(output_path.to_string_lossy().to_string());
+        }
+        Some(output_path)
+    };
+    info!("Args: {:?}", redact(&args));
+
+    if ev.dry_run {
+        info!("Dry run, skipping conversion");
+        return Ok(PdfResponse {
+            success: true,
+            command_preview: Some(args),
+            ..Default::default()
+        });
+    }
+
+    if let Some(s3_details) = ev.output().filter(|output| output.skip_if_exists) {
+        if object_exists(s3_details).code(ErrorCode::UploadFailed)? {
+            info!(
+                "s3://{}/{} already exists, skipping conversion",
+                s3_details.bucket, s3_details.object_key
+            );
+            return Ok(PdfResponse {
+                success: true,
+                skipped: true,
+                object_key: Some(s3_details.object_key.clone()),
+                ..Default::default()
+            });
+        }
+    }
+
+    let installation = Installation::resolve()?;
+    info!("wkhtmltopdf path: {}", installation.wkhtmltopdf_path);
+    let fontconfig_path = fonts::fontconfig_path(&installation).code(ErrorCode::Internal)?;
+    info!("fontconfig path: {}", fontconfig_path);
+    let wkhtmltopdf_version = installation.version();
+    let binary_path = if ev.output_format.is_image() {
+        let wkhtmltoimage_path = installation
+            .sibling("wkhtmltoimage")
+            .ok_or_else(|| {
+                anyhow!(
+                    "Image output requires wkhtmltoimage next to {}",
+                    installation.wkhtmltopdf_path
+                )
+            })
+            .code(ErrorCode::InvalidRequest)?;
+        info!("wkhtmltoimage path: {}", wkhtmltoimage_path);
+        wkhtmltoimage_path
+    } else {
+        installation.wkhtmltopdf_path.clone()
+    };
+    let qpdf_path = if ev.encryption.is_some()
+        || ev.watermark.is_some()
+        || !groups.is_empty()
+        || page_range.is_some()
     {
-        let task_root = env::var("LAMBDA_TASK_ROOT").unwrap().to_string();
-        (
-            task_root.clone() + WKHTMLTOPDF_BUNDLED_PATH,
-            task_root + "/fonts",
+        Some(
+            installation
+                .sibling("qpdf")
+                .ok_or_else(|| {
+                    anyhow!(
+                        "Encryption, watermarks, page groups and pageRange require qpdf next to {}",
+                        installation.wkhtmltopdf_path
+                    )
+                })
+                .code(ErrorCode::InvalidRequest)?,
         )
     } else {
-        (
-            "/usr/bin/wkhtmltopdf".to_owned(),
-            "/usr/share/fonts".to_owned(),
+        None
+    };
+    let gs_path = if ev.pdf_a {
+        Some(
+            installation
+                .sibling("gs")
+                .ok_or_else(|| {
+                    anyhow!(
+                        "PDF/A requires Ghostscript (gs) next to {}",
+                        installation.wkhtmltopdf_path
+                    )
+                })
+                .code(ErrorCode::InvalidRequest)?,
         )
+    } else {
+        None
     };
-    info!("wkhtmltopdf path: {}", wkhtmltopdf_path);
-    info!("fontconfig path: {}", fontconfig_path);
 
-    let output = Command::new(wkhtmltopdf_path)
-        .env("FONTCONFIG_PATH", fontconfig_path)
-        .stdin(Stdio::null())
-        .args(&args)
-        .output()?;
+    let timeout = match ev.timeout_seconds {
+        Some(timeout) => Some(timeout),
+        None => match env::var("WKHTMLTOPDF_TIMEOUT") {
+            Ok(timeout) => Some(
+                timeout
+                    .parse::<u64>()
+                    .map_err(|e| anyhow!("Invalid WKHTMLTOPDF_TIMEOUT: {}", e.to_string()))?,
+            ),
+            Err(_) => None,
+        },
+    };
+    let requested_timeout = timeout.map(Duration::from_secs);
+    // kill wkhtmltopdf in time to send a Timeout response, rather than being killed by Lambda
+    let lambda_budget = lambda_budget(ctx)?;
+    let deadline_limited = match (requested_timeout, lambda_budget) {
+        (Some(requested_timeout), Some(lambda_budget)) => lambda_budget < requested_timeout,
+        (None, Some(_)) => true,
+        (_, None) => false,
+    };
+    let timeout = if deadline_limited {
+        lambda_budget
+    } else {
+        requested_timeout
+    };
+    if let Some(timeout) = timeout {
+        info!(
+            "wkhtmltopdf timeout: {:.1} seconds{}",
+            timeout.as_secs_f64(),
+            if deadline_limited {
+                ", limited by the Lambda deadline"
+            } else {
+                ""
+            }
+        );
+    }
 
+    let permit = renders::acquire().code(ErrorCode::TooBusy)?;
+    let render_start = Instant::now();
+    let mut render_segment = Subsegment::start("wkhtmltopdf.render", ctx.xray_trace_id.as_deref());
+    render_segment.annotate("page_count", page_count);
+    let spawn = |args: &[String]| {
+        Command::new(&binary_path)
+            .env("FONTCONFIG_PATH", &fontconfig_path)
+            .env("TMPDIR", temp_dir())
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .args(args)
+            .spawn()
+    };
+    let mut failed_group = None;
+    let (status, stdout, stderr, pending_upload) = if let Some(s3_details) = stream_output {
+        let child = spawn(&args)?;
+        let s3_details = s3_details.clone();
+        let (status, pending_upload, stderr) = wait_with_timeout(child, timeout, move |pipe| {
+            let mut reader = HashingReader::new(pipe);
+            let pending_upload = stream_upload(&mut reader, &s3_details);
+            if pending_upload.is_err() {
+                // keep draining, otherwise wkhtmltopdf blocks on the full pipe and never exits
+                let _ = io::copy(&mut reader, &mut io::sink());
+            }
+            Ok((pending_upload?, reader.finish()))
+        })?;
+        (status, Vec::new(), stderr, Some(pending_upload))
+    } else if groups.is_empty() {
+        let (status, stdout, stderr) = wait_with_timeout(spawn(&args)?, timeout, read_to_end)?;
+        (status, stdout, stderr, None)
+    } else {
+        // the timeout covers all groups together
+        let deadline = timeout.map(|timeout| render_start + timeout);
+        let (mut status, mut stdout, mut stderr) = (None, Vec::new(), Vec::new());
+        for group in &groups {
+            info!("Rendering page group {}", group.name);
+            let remaining =
+                deadline.map(|deadline| deadline.saturating_duration_since(Instant::now()));
+            let (group_status, group_stdout, mut group_stderr) =
+                wait_with_timeout(spawn(&group.args)?, remaining, read_to_end)?;
+            status = group_status;
+            stdout = group_stdout;
+            stderr.append(&mut group_stderr);
+            if !status.map(|status| status.success()).unwrap_or(false) {
+                failed_group = Some(group.name.clone());
+                break;
+            }
+        }
+        if let (None, Some(qpdf_path), Some(output_path)) =
+            (&failed_group, &qpdf_path, &output_path)
+        {
+            let inputs = groups
+                .iter()
+                .map(|group| group.output_path.clone())
+                .collect::<Vec<_>>();
+            qpdf::merge(qpdf_path, &inputs, output_path).code(ErrorCode::RenderFailed)?;
+        }
+        (status, stdout, stderr, None)
+    };
+    metrics.render = Some(render_start.elapsed());
+    render_segment.annotate(
+        "exit_status",
+        match status.as_ref().map(ExitStatus::code) {
+            Some(Some(code)) => code.to_string(),
+            Some(None) => "signal".to_owned(),
+            None => "timeout".to_owned(),
+        },
+    );
+    drop(render_segment);
+    drop(permit);
+    let pending_upload = pending_upload.transpose().code(ErrorCode::UploadFailed)?;
+    let status = match status {
+        Some(status) => status,
+        None => {
+            abort_upload(
+                pending_upload.map(|(pending_upload, _)| pending_upload),
+                stream_output,
+            );
+            let mut message = if deadline_limited {
+                "wkhtmltopdf was killed to respond before the Lambda deadline".to_owned()
+            } else {
+                format!(
+                    "wkhtmltopdf exceeded timeout of {} seconds",
+                    timeout.unwrap_or_default().as_secs()
+                )
+            };
+            if let Some(group) = &failed_group {
+                message = format!("Page group {}: {}", group, message);
+            }
+            error!("{}", message);
+            return Ok(PdfResponse {
+                success: false,
+                messages: vec![message],
+                error_code: Some(ErrorCode::Timeout),
+                wkhtmltopdf_version,
+                render_ms: metrics.render.map(millis),
+                ..Default::default()
+            });
+        }
+    };
+    let stdout = redact_output(&String::from_utf8_lossy(&stdout), &args);
+    let stderr = redact_output(&String::from_utf8_lossy(&stderr), &args);
+
+    let pages = ev
+        .pages
+        .iter()
+        .chain(ev.groups.iter().flat_map(|group| group.pages.iter()))
+        .collect::<Vec<_>>();
     let mut response = PdfResponse {
-        success: output.status.success(),
+        success: status.success(),
+        wkhtmltopdf_version,
+        pages: stderr::page_summaries(&pages, &stderr, status.success()),
+        render_ms: metrics.render.map(millis),
         ..Default::default()
     };
-    if output.status.success() {
+    if status.success() {
         info!("Successfully converted HTML to PDF");
-        upload(&mut file, &ev.output)?;
-    } else {
-        error!("wkhtmltopdf exited with {}", output.status);
-        error!(
-            "wkhtmltopdf stdout: {}",
-            String::from_utf8_lossy(&output.stdout)
+        response.warnings = stderr::warnings(&stderr);
+        for page in response
+            .pages
+            .iter()
+            .filter(|page| page.load_status == "failed")
+        {
+            response.warnings.push(format!(
+                "Page {} ({}) failed to load",
+                page.index, page.source
+            ));
+        }
+        for warning in &response.warnings {
+            warn!("wkhtmltopdf: {}", warning);
+        }
+        let output_path = match (&page_range, &qpdf_path, output_path) {
+            (Some(page_range), Some(qpdf_path), Some(output_path)) => {
+                let page_count =
+                    qpdf::page_count(qpdf_path, &output_path).code(ErrorCode::RenderFailed)?;
+                if let Some((_, end)) = page_range.iter().find(|(_, end)| *end > page_count) {
+                    return Err(anyhow!(
+                        "pageRange {} is out of bounds, page {} was requested but the PDF has {} pages",
+                        ev.page_range.as_deref().unwrap_or_default(),
+                        end,
+                        page_count
+                    ))
+                    .code(ErrorCode::InvalidRequest);
+                }
+                let selected_path = files.write("wkhtmltopdf-selected", ".pdf", &[])?;
+                qpdf::select_pages(qpdf_path, &output_path, page_range, &selected_path)
+                    .code(ErrorCode::RenderFailed)?;
+                // frees up the space of the full render right away, rather than with the other
+                // temp files
+                if let Err(e) = fs::remove_file(&output_path) {
+                    warn!("Failed to remove {}: {}", output_path.display(), e);
+                }
+                Some(selected_path)
+            }
+            (_, _, output_path) => output_path,
+        };
+        let output_path = match (&ev.watermark, &qpdf_path, output_path) {
+            (Some(watermark), Some(qpdf_path), Some(output_path)) => {
+                let overlay_path = match &watermark.pdf_base64 {
+                    Some(pdf_base64) => files.write(
+                        "wkhtmltopdf-watermark",
+                        ".pdf",
+                        &decode_base64(pdf_base64).code(ErrorCode::InvalidRequest)?,
+                    )?,
+                    None => {
+                        let overlay_path = files.write("wkhtmltopdf-watermark", ".pdf", &[])?;
+                        pdf::write_watermark(watermark, &overlay_path).code(ErrorCode::Internal)?;
+                        overlay_path
+                    }
+                };
+                let stamped_path = files.write("wkhtmltopdf-stamped", ".pdf", &[])?;
+                qpdf::overlay(qpdf_path, &output_path, &overlay_path, &stamped_path)
+                    .code(ErrorCode::RenderFailed)?;
+                Some(stamped_path)
+            }
+            (_, _, output_path) => output_path,
+        };
+        if let (Some(metadata), Some(output_path)) = (&ev.metadata, &output_path) {
+            // the PDF is still usable without metadata, so this doesn't fail the request
+            if let Err(e) = pdf::set_metadata(output_path, metadata) {
+                let warning = format!("Failed to set PDF metadata: {}", e);
+                warn!("{}", warning);
+                response.warnings.push(warning);
+            }
+        }
+        // after the metadata, so that Ghostscript carries it over into the XMP metadata PDF/A needs
+        let output_path = match (&gs_path, output_path) {
+            (Some(gs_path), Some(output_path)) => {
+                let pdf_a_path = files.write("wkhtmltopdf-pdfa", ".pdf", &[])?;
+                let warnings = ghostscript::convert_to_pdf_a(gs_path, &output_path, &pdf_a_path)
+                    .code(ErrorCode::RenderFailed)?;
+                for warning in warnings {
+                    let warning = format!("Ghostscript: {}", warning);
+                    warn!("{}", warning);
+                    response.warnings.push(warning);
+                }
+                Some(pdf_a_path)
+            }
+            (_, output_path) => output_path,
+        };
+        let output_path = match (&ev.encryption, &qpdf_path, output_path) {
+            (Some(encryption), Some(qpdf_path), Some(output_path)) => {
+                let encrypted_path = files.write("wkhtmltopdf-encrypted", ".pdf", &[])?;
+                qpdf::encrypt(qpdf_path, &output_path, &encrypted_path, encryption)
+                    .code(ErrorCode::RenderFailed)?;
+                Some(encrypted_path)
+            }
+            (_, _, output_path) => output_path,
+        };
+        let stats = match (ev.output(), &output_path, pending_upload) {
+            (Some(s3_details), _, Some((pending_upload, stats))) => {
+                let _upload_segment = upload_segment(s3_details, &stats, ctx);
+                let upload_start = Instant::now();
+                let uploaded = pending_upload
+                    .complete(s3_details)
+                    .map_err(upload_failed)
+                    .code(ErrorCode::UploadFailed)?;
+                response.etag = uploaded.etag;
+                response.version_id = uploaded.version_id;
+                metrics.upload = Some(upload_start.elapsed());
+                stats
+            }
+            (Some(s3_details), Some(output_path), None) => {
+                let contents = read_output(output_path)?;
+                let stats = OutputStats::new(&contents);
+                let upload_start = Instant::now();
+                let inline = if also_inline {
+                    Some(if ev.compress_response {
+                        gzip(&contents)?
+                    } else {
+                        contents.clone()
+                    })
+                } else {
+                    None
+                };
+                if ev.outputs.len() == 1 {
+                    let _upload_segment = upload_segment(s3_details, &stats, ctx);
+                    let uploaded = upload(contents, s3_details, ev.output_format.content_type())
+                        .map_err(upload_failed)
+                        .code(ErrorCode::UploadFailed)?;
+                    response.etag = uploaded.etag;
+                    response.version_id = uploaded.version_id;
+                } else {
+                    upload_destinations(ev, ctx, &contents, &stats, &mut response);
+                }
+                metrics.upload = Some(upload_start.elapsed());
+                if let Some(inline) = inline {
+                    // the upload already succeeded, so a PDF too large to return isn't fatal
+                    match encode_inline(&inline) {
+                        Ok(pdf_base64) => {
+                            response.pdf_base64 = Some(pdf_base64);
+                            if ev.compress_response {
+                                response.content_encoding = Some("gzip".to_owned());
+                            }
+                        }
+                        Err(e) => {
+                            let warning = format!("Only uploaded: {}", e);
+                            warn!("{}", warning);
+                            response.warnings.push(warning);
+                        }
+                    }
+                }
+                stats
+            }
+            (None, Some(output_path), _) => {
+                let contents = read_output(output_path)?;
+                let stats = OutputStats::new(&contents);
+                match &local_output {
+                    Some(local_output) => {
+                        local::write(local_output, &contents)?;
+                        response.output_path = Some(local_output.to_string_lossy().to_string());
+                    }
+                    None if ev.compress_response => {
+                        response.pdf_base64 = Some(encode_inline(&gzip(&contents)?)?);
+                        response.content_encoding = Some("gzip".to_owned());
+                    }
+                    None => response.pdf_base64 = Some(encode_inline(&contents)?),
+                }
+                stats
+            }
+            _ => unreachable!(),
+        };
+        info!(
+            "PDF has {} bytes and {} pages, SHA-256 {}",
+            stats.bytes,
+            stats
+                .page_count
+                .map(|page_count| page_count.to_string())
+                .unwrap_or_else(|| "unknown".to_owned()),
+            stats.sha256
         );
-        error!(
-            "wkhtmltopdf stderr: {}",
-            String::from_utf8_lossy(&output.stderr)
+        response.object_key = ev.output().map(|output| output.object_key.clone());
+        if let Some(s3_details) = ev.output().filter(|_| ev.outputs.len() == 1) {
+            if let Some(presign_seconds) = s3_details.presign_seconds {
+                response.download_url = Some(
+                    presign(s3_details, Duration::from_secs(presign_seconds))
+                        .code(ErrorCode::UploadFailed)?,
+                );
+            }
+        }
+        response.upload_ms = metrics.upload.map(millis);
+        response.output_bytes = Some(stats.bytes);
+        metrics.output_bytes = Some(stats.bytes);
+        response.sha256 = Some(stats.sha256);
+        response.content_md5 = Some(stats.md5);
+        response.page_count = stats.page_count;
+    } else {
+        abort_upload(
+            pending_upload.map(|(pending_upload, _)| pending_upload),
+            stream_output,
         );
-        if !output.stdout.is_empty() {
-            response
-                .messages
-                .push(String::from_utf8_lossy(&output.stdout).to_string());
+        response.error_code = Some(ErrorCode::RenderFailed);
+        response.progress = stderr::progress(&stderr);
+        if let Some(group) = &failed_group {
+            let message = format!("Page group {} failed to render", group);
+            error!("{}", message);
+            response.messages.push(message);
         }
-        if !output.stderr.is_empty() {
-            response
-                .messages
-                .push(String::from_utf8_lossy(&output.stderr).to_string());
+        error!("wkhtmltopdf exited with {}", status);
+        error!("wkhtmltopdf stdout: {}", stdout);
+        error!("wkhtmltopdf stderr: {}", stderr);
+        if !stdout.is_empty() {
+            response.messages.push(stdout);
+        }
+        if !stderr.is_empty() {
+            response.messages.push(stderr);
         }
     }
 
     Ok(response)
 }
 
-fn build_args(ev: &PdfRequest) -> anyhow::Result<(Vec<String>, Vec<NamedTempFile>)> {
+/// The time left until the Lambda deadline, minus `TIMEOUT_MARGIN_MS` for cleaning up and
+/// responding. `None` outside of Lambda, where there's no deadline.
+fn lambda_budget(ctx: &lambda_runtime::Context) -> anyhow::Result<Option<Duration>> {
+    if ctx.deadline <= 0 {
+        return Ok(None);
+    }
+    let margin = match env::var("TIMEOUT_MARGIN_MS") {
+        Ok(margin) => margin
+            .parse::<u64>()
+            .map_err(|e| anyhow!("Invalid TIMEOUT_MARGIN_MS: {}", e.to_string()))?,
+        Err(_) => DEFAULT_TIMEOUT_MARGIN_MS,
+    };
+    let remaining = ctx.get_time_remaining_millis().max(0) as u64;
+    Ok(Some(Duration::from_millis(
+        remaining.saturating_sub(margin),
+    )))
+}
+
+/// Uploads the output to each of several destinations, one after another. A failed destination
+/// doesn't stop the others, but fails the response.
+fn upload_destinations(
+    ev: &PdfRequest,
+    ctx: &lambda_runtime::Context,
+    contents: &[u8],
+    stats: &OutputStats,
+    response: &mut PdfResponse,
+) {
+    for s3_details in &ev.outputs {
+        let _upload_segment = upload_segment(s3_details, stats, ctx);
+        let uploaded = upload(
+            contents.to_vec(),
+            s3_details,
+            ev.output_format.content_type(),
+        )
+        .and_then(|uploaded| {
+            let download_url = s3_details
+                .presign_seconds
+                .map(|presign_seconds| presign(s3_details, Duration::from_secs(presign_seconds)))
+                .transpose()?;
+            Ok((uploaded, download_url))
+        });
+        let destination = match uploaded {
+            Ok((uploaded, download_url)) => Destination {
+                success: true,
+                bucket: s3_details.bucket.clone(),
+                object_key: s3_details.object_key.clone(),
+                etag: uploaded.etag,
+                version_id: uploaded.version_id,
+                download_url,
+                message: None,
+            },
+            Err(e) => {
+                let message = format!(
+                    "Rendered successfully, but the upload to s3://{}/{} failed: {}",
+                    s3_details.bucket, s3_details.object_key, e
+                );
+                error!("{}", message);
+                response.success = false;
+                response.error_code = Some(ErrorCode::UploadFailed);
+                response.messages.push(message.clone());
+                Destination {
+                    success: false,
+                    bucket: s3_details.bucket.clone(),
+                    object_key: s3_details.object_key.clone(),
+                    etag: None,
+                    version_id: None,
+                    download_url: None,
+                    message: Some(message),
+                }
+            }
+        };
+        response.destinations.push(destination);
+    }
+    if let Some(primary) = response.destinations.first().filter(|d| d.success) {
+        response.etag = primary.etag.clone();
+        response.version_id = primary.version_id.clone();
+        response.download_url = primary.download_url.clone();
+    }
+}
+
+fn upload_segment(
+    s3_details: &S3Details,
+    stats: &OutputStats,
+    ctx: &lambda_runtime::Context,
+) -> Subsegment {
+    let mut segment = Subsegment::start("s3.upload", ctx.xray_trace_id.as_deref());
+    segment.annotate("object_key", s3_details.object_key.as_str());
+    segment.annotate("output_bytes", stats.bytes);
+    if let Some(page_count) = stats.page_count {
+        segment.annotate("page_count", page_count);
+    }
+    segment
+}
+
+fn abort_upload(pending_upload: Option<PendingUpload>, s3_details: Option<&S3Details>) {
+    if let (Some(pending_upload), Some(s3_details)) = (pending_upload, s3_details) {
+        if let Err(e) = pending_upload.abort(s3_details) {
+            error!("{}", e);
+        }
+    }
+}
+
+fn read_to_end(mut pipe: ChildStdout) -> Vec<u8> {
+    let mut stdout = Vec::new();
+    let _ = pipe.read_to_end(&mut stdout);
+    stdout
+}
+
+/// Waits for the child to exit while `read_stdout` consumes its stdout, or kills and reaps it
+/// once the timeout elapses, in which case the returned exit status is `None`.
+fn wait_with_timeout<T: Send + 'static>(
+    mut child: Child,
+    timeout: Option<Duration>,
+    read_stdout: impl FnOnce(ChildStdout) -> T + Send + 'static,
+) -> anyhow::Result<(Option<ExitStatus>, T, Vec<u8>)> {
+    // drain the pipes in the background so that the child never blocks on a full pipe buffer
+    let stdout_pipe = child
+        .stdout
+        .take()
+        .ok_or_else(|| anyhow!("Failed to open wkhtmltopdf stdout"))?;
+    let stdout_reader = thread::spawn(move || read_stdout(stdout_pipe));
+    let mut stderr_pipe = child.stderr.take();
+    let stderr_reader = thread::spawn(move || {
+        let mut stderr = Vec::new();
+        if let Some(ref mut pipe) = stderr_pipe {
+            let _ = pipe.read_to_end(&mut stderr);
+        }
+        stderr
+    });
+
+    let status = match timeout {
+        Some(timeout) => {
+            let deadline = Instant::now() + timeout;
+            loop {
+                if let Some(status) = child.try_wait()? {
+                    break Some(status);
+                }
+                if Instant::now() >= deadline {
+                    warn!("Killing wkhtmltopdf (pid {})", child.id());
+                    child.kill()?;
+                    child.wait()?;
+                    break None;
+                }
+                thread::sleep(Duration::from_millis(50));
+            }
+        }
+        None => Some(child.wait()?),
+    };
+
+    let stdout = stdout_reader
+        .join()
+        .map_err(|_| anyhow!("Failed to read wkhtmltopdf stdout"))?;
+    let stderr = stderr_reader
+        .join()
+        .map_err(|_| anyhow!("Failed to read wkhtmltopdf stderr"))?;
+
+    Ok((status, stdout, stderr))
+}
+
+/// Owns the temp files of a conversion and removes them once dropped, however the conversion
+/// ends.
+pub struct TempFiles {
+    files: Vec<NamedTempFile>,
+    dirs: Vec<TempDir>,
+    dry_run: bool,
+    placeholders: usize,
+}
+
+impl TempFiles {
+    /// In dry-run mode no files are written and deterministic placeholder paths are handed out
+    /// instead.
+    pub fn new(dry_run: bool) -> Self {
+        TempFiles {
+            files: Vec::new(),
+            dirs: Vec::new(),
+            dry_run,
+            placeholders: 0,
+        }
+    }
+
+    pub fn is_dry_run(&self) -> bool {
+        self.dry_run
+    }
+
+    pub fn write(
+        &mut self,
+        prefix: &str,
+        suffix: &str,
+        contents: &[u8],
+    ) -> anyhow::Result<PathBuf> {
+        self.write_in(None, prefix, suffix, contents)
+    }
+
+    /// Like `write`, but places the file in `dir` (usually one from `create_dir`) so that
+    /// relative references resolve against its siblings.
+    pub fn write_in(
+        &mut self,
+        dir: Option<&Path>,
+        prefix: &str,
+        suffix: &str,
+        contents: &[u8],
+    ) -> anyhow::Result<PathBuf> {
+        if self.dry_run {
+            return Ok(self.placeholder(prefix, suffix));
+        }
+
+        let mut builder = Builder::new();
+        builder.prefix(prefix).suffix(suffix);
+        let file = match dir {
+            Some(dir) => builder.tempfile_in(dir),
+            None => builder.tempfile_in(temp_dir()),
+        };
+        let mut file =
+            file.map_err(|e| anyhow!("Failed to create temp file: {}", e.to_string()))?;
+        file.write_all(contents)
+            .map_err(|e| anyhow!("Failed to write to temp file: {}", e.to_string()))?;
+        let path = file.path().to_owned();
+        self.files.push(file);
+        Ok(path)
+    }
+
+    pub fn create_dir(&mut self, prefix: &str) -> anyhow::Result<PathBuf> {
+        if self.dry_run {
+            return Ok(self.placeholder(prefix, ""));
+        }
+
+        let dir = Builder::new()
+            .prefix(prefix)
+            .tempdir_in(temp_dir())
+            .map_err(|e| anyhow!("Failed to create temp directory: {}", e.to_string()))?;
+        let path = dir.path().to_owned();
+        self.dirs.push(dir);
+        Ok(path)
+    }
+
+    fn placeholder(&mut self, prefix: &str, suffix: &str) -> PathBuf {
+        let placeholder = format!("<{}-{}{}>", prefix, self.placeholders, suffix);
+        self.placeholders += 1;
+        PathBuf::from(placeholder)
+    }
+}
+
+impl Drop for TempFiles {
+    fn drop(&mut self) {
+        for file in self.files.drain(..) {
+            let path = file.path().to_owned();
+            if let Err(e) = file.close() {
+                warn!("Failed to remove temp file {}: {}", path.display(), e);
+            }
+        }
+        for dir in self.dirs.drain(..) {
+            let path = dir.path().to_owned();
+            if let Err(e) = dir.close() {
+                warn!("Failed to remove temp directory {}: {}", path.display(), e);
+            }
+        }
+    }
+}
+
+/// The complete wkhtmltopdf arguments of a page group, writing to a PDF of its own.
+struct GroupArgs {
+    name: String,
+    args: Vec<String>,
+    output_path: PathBuf,
+}
+
+/// Runs the request's checks without rendering anything or accessing S3, collecting all problems
+/// instead of stopping at the first one.
+fn validate(ev: &mut PdfRequest) -> PdfResponse {
+    let mut problems = Vec::new();
+    if let Err(e) = resolve_object_key(ev) {
+        problems.push(e.to_string());
+    }
+    if let Err(e) = resolve_local_output(ev) {
+        problems.push(e.to_string());
+    }
+    if let Some(callback_url) = &ev.callback_url {
+        if let Err(e) = validate_url(callback_url, ev.allow_private_urls) {
+            problems.push(e.to_string());
+        }
+    }
+    problems.extend(output_problems(ev).iter().map(ToString::to_string));
+    problems.extend(combination_problems(ev).iter().map(ToString::to_string));
+
+    let mut units = Vec::new();
+    if ev.groups.is_empty() {
+        units.push((String::new(), ev.clone()));
+    } else {
+        if !ev.pages.is_empty() {
+            problems.push("pages and groups are mutually exclusive".to_owned());
+        }
+        for (i, group) in ev.groups.iter().enumerate() {
+            let name = group.name.clone().unwrap_or_else(|| (i + 1).to_string());
+            let group_ev = PdfRequest {
+                pages: group.pages.clone(),
+                groups: Vec::new(),
+                ..ev.clone()
+            };
+            units.push((format!("Page group {}: ", name), group_ev));
+        }
+    }
+    let mut files = TempFiles::new(true);
+    for (prefix, unit) in &units {
+        for problem in field_problems(unit) {
+            problems.push(format!("{}{}", prefix, problem));
+        }
+        for (i, page) in unit.pages.iter().enumerate() {
+            if let Err(e) = check_page(unit, page, &mut files) {
+                problems.push(format!("{}Page {}: {}", prefix, i, e));
+            }
+        }
+    }
+
+    if problems.is_empty() {
+        info!("Request is valid");
+    } else {
+        for problem in &problems {
+            info!("Invalid request: {}", problem);
+        }
+    }
+    PdfResponse {
+        success: problems.is_empty(),
+        error_code: if problems.is_empty() {
+            None
+        } else {
+            Some(ErrorCode::InvalidRequest)
+        },
+        messages: problems,
+        ..Default::default()
+    }
+}
+
+fn output_problems(ev: &PdfRequest) -> Vec<anyhow::Error> {
+    let mut problems = Vec::new();
+    for (i, output) in ev.outputs.iter().enumerate() {
+        for problem in destination_problems(output) {
+            problems.push(if ev.outputs.len() == 1 {
+                problem
+            } else {
+                anyhow!("Output {}: {}", i, problem)
+            });
+        }
+        if ev.outputs[..i].iter().any(|other| {
+            other.bucket == output.bucket
+                && other.object_key == output.object_key
+                && other.endpoint == output.endpoint
+        }) {
+            problems.push(anyhow!(
+                "output lists s3://{}/{} more than once",
+                output.bucket,
+                output.object_key
+            ));
+        }
+    }
+    if ev.outputs.len() > 1
+        && ev
+            .outputs
+            .iter()
+            .any(|output| output.stream_upload || output.skip_if_exists)
+    {
+        problems.push(anyhow!(
+            "streamUpload and skipIfExists can't be combined with several outputs"
+        ));
+    }
+    problems
+}
+
+fn destination_problems(output: &S3Details) -> Vec<anyhow::Error> {
+    let mut problems = Vec::new();
+    if output.skip_if_exists && output.fail_if_exists {
+        problems.push(anyhow!(
+            "skipIfExists and failIfExists are mutually exclusive"
+        ));
+    }
+    problems.extend(
+        vec![
+            check_location(output),
+            tagging(output).map(drop),
+            check_presign_seconds(output).map(drop),
+            check_expires(output).map(drop),
+            check_content_type(output),
+            check_region(output).map(drop),
+            storage_class(output).map(drop),
+        ]
+        .into_iter()
+        .filter_map(Result::err),
+    );
+    problems
+}
+
+/// Checks a single page's options and source like `build_args` does, with S3 sources treated as
+/// empty.
+fn check_page(ev: &PdfRequest, page: &PdfPage, files: &mut TempFiles) -> anyhow::Result<()> {
+    check_allowed(&page.options, allowlist().as_deref())?;
+    let options = own_page_options(ev, page)?;
+    check_values(&options)?;
+    let options = dedupe(&merge_defaults(&ev.default_page_options, &options));
     let mut args = Vec::new();
-    for option in &ev.options {
-        args.push(option.name.clone());
-        if let Some(value) = &option.value {
-            args.push(value.clone());
+    if page.page_type == PageType::TOC {
+        push_toc_args(&mut args, page, &options, files)
+    } else {
+        let download = page.html_s3.as_ref().map(|_| Vec::new());
+        push_page_args(&mut args, ev, page, &options, download, files)
+    }
+}
+
+/// Features which can't be combined, and the settings of the post-processing steps.
+fn combination_problems(ev: &PdfRequest) -> Vec<anyhow::Error> {
+    let mut problems = Vec::new();
+    let stream_output = ev.output().filter(|output| output.stream_upload);
+    if !ev.groups.is_empty() && (stream_output.is_some() || ev.output_format.is_image()) {
+        problems.push(anyhow!(
+            "groups can't be combined with streamUpload or image output"
+        ));
+    }
+    match ev.output_mode {
+        Some(OutputMode::Upload) | Some(OutputMode::UploadAndInline) if ev.outputs.is_empty() => {
+            problems.push(anyhow!(
+                "outputMode upload and uploadAndInline require output"
+            ))
+        }
+        Some(OutputMode::Inline) if !ev.outputs.is_empty() || ev.local_output.is_some() => problems
+            .push(anyhow!(
+                "outputMode inline can't be combined with output or localOutput"
+            )),
+        Some(OutputMode::UploadAndInline) if stream_output.is_some() => problems.push(anyhow!(
+            "outputMode uploadAndInline can't be combined with streamUpload"
+        )),
+        _ => {}
+    }
+    if ev.compress_response
+        && ev.output_mode != Some(OutputMode::UploadAndInline)
+        && (!ev.outputs.is_empty() || ev.local_output.is_some())
+    {
+        problems.push(anyhow!(
+            "compressResponse only applies to PDFs returned inline"
+        ));
+    }
+    if ev.output_format.is_image()
+        && (stream_output.is_some() || ev.metadata.is_some() || ev.encryption.is_some())
+    {
+        problems.push(anyhow!(
+            "streamUpload, metadata and encryption only apply to PDF output"
+        ));
+    }
+    if ev.pdf_a
+        && (stream_output.is_some() || ev.output_format.is_image() || ev.encryption.is_some())
+    {
+        problems.push(anyhow!(
+            "pdfA can't be combined with streamUpload, image output or encryption"
+        ));
+    }
+    if ev.metadata.is_some() && stream_output.is_some() {
+        problems.push(anyhow!("metadata can't be combined with streamUpload"));
+    }
+    if let Some(watermark) = &ev.watermark {
+        if stream_output.is_some() || ev.output_format.is_image() {
+            problems.push(anyhow!(
+                "watermark can't be combined with streamUpload or image output"
+            ));
+        }
+        if let Err(e) = pdf::check_watermark(watermark) {
+            problems.push(e);
+        }
+    }
+    if let Some(page_range) = &ev.page_range {
+        if stream_output.is_some() || ev.output_format.is_image() {
+            problems.push(anyhow!(
+                "pageRange can't be combined with streamUpload or image output"
+            ));
+        }
+        if let Err(e) = qpdf::parse_page_range(page_range) {
+            problems.push(e);
+        }
+    }
+    if let Some(encryption) = &ev.encryption {
+        if stream_output.is_some() {
+            problems.push(anyhow!("encryption can't be combined with streamUpload"));
+        }
+        if let Err(e) = qpdf::bit_length(encryption) {
+            problems.push(e);
         }
     }
+    problems
+}
+
+/// Builds the arguments of every page group as if it was a request of its own, empty for
+/// requests with plain `pages`.
+fn build_group_args(ev: &PdfRequest, files: &mut TempFiles) -> anyhow::Result<Vec<GroupArgs>> {
+    if ev.groups.is_empty() {
+        return Ok(Vec::new());
+    }
+    if !ev.pages.is_empty() {
+        return Err(anyhow!("pages and groups are mutually exclusive"));
+    }
+    let mut groups = Vec::new();
+    for (i, group) in ev.groups.iter().enumerate() {
+        let name = group.name.clone().unwrap_or_else(|| (i + 1).to_string());
+        let group_ev = PdfRequest {
+            pages: group.pages.clone(),
+            groups: Vec::new(),
+            ..ev.clone()
+        };
+        let mut args = build_args(&group_ev, files)
+            .map_err(|e| anyhow!("Page group {}: {}", name, e.to_string()))?;
+        let output_path = files.write("wkhtmltopdf-group", ".pdf", &[])?;
+        args.push(output_path.to_string_lossy().to_string());
+        groups.push(GroupArgs {
+            name,
+            args,
+            output_path,
+        });
+    }
+    Ok(groups)
+}
+
+/// Problems with the request-level fields and options, which don't depend on the pages.
+fn field_problems(ev: &PdfRequest) -> Vec<anyhow::Error> {
+    let mut problems = Vec::new();
+    if !ev.pages.iter().any(|page| page.page_type == PageType::PAGE) {
+        problems.push(anyhow!(
+            "At least one page of type PAGE is required, a cover or TOC alone has no content"
+        ));
+    }
+    if ev.output_format.is_image() {
+        if let Err(e) = check_image_request(ev) {
+            problems.push(e);
+        }
+    }
+    match &ev.proxy {
+        Some(proxy) => {
+            if let Err(e) = validate_proxy(proxy) {
+                problems.push(e);
+            }
+        }
+        None if ev.proxy_hostname_lookup => {
+            problems.push(anyhow!("proxyHostnameLookup requires a proxy"));
+        }
+        None => {}
+    }
+    if let Some(user_agent) = &ev.user_agent {
+        if user_agent.is_empty() || user_agent.chars().any(char::is_control) {
+            problems.push(anyhow!(
+                "userAgent must not be empty or contain control characters"
+            ));
+        }
+    }
+    if ev.ignore_load_errors
+        && ev.page_error_policy.is_some()
+        && ev.page_error_policy != Some(PageErrorPolicy::Ignore)
+    {
+        problems.push(anyhow!(
+            "ignoreLoadErrors can't be combined with another pageErrorPolicy"
+        ));
+    }
+    if let Some(zoom) = ev.zoom {
+        if !(MIN_ZOOM..=MAX_ZOOM).contains(&zoom) {
+            problems.push(anyhow!(
+                "zoom must be between {} and {}, got {}",
+                MIN_ZOOM,
+                MAX_ZOOM,
+                zoom
+            ));
+        }
+    }
+    if let Some(dpi) = ev.dpi {
+        if !(MIN_DPI..=MAX_DPI).contains(&dpi) {
+            problems.push(anyhow!(
+                "dpi must be between {} and {}, got {}",
+                MIN_DPI,
+                MAX_DPI,
+                dpi
+            ));
+        }
+    }
+    if let Some(title) = &ev.title {
+        if title.starts_with("--") || title.chars().any(char::is_control) {
+            problems.push(anyhow!(
+                "title must not start with -- or contain control characters"
+            ));
+        }
+    }
+
+    for (name, block) in &[("header", &ev.header), ("footer", &ev.footer)] {
+        if let Some(block) = block {
+            for text in [&block.left, &block.center, &block.right]
+                .iter()
+                .copied()
+                .flatten()
+            {
+                if text.starts_with("--") || text.chars().any(char::is_control) {
+                    problems.push(anyhow!(
+                        "{} text must not start with -- or contain control characters",
+                        name
+                    ));
+                }
+            }
+            if let Some(spacing) = block.spacing {
+                if !(0.0..=MAX_HEADER_SPACING).contains(&spacing) {
+                    problems.push(anyhow!(
+                        "{}.spacing must be between 0 and {}, got {}",
+                        name,
+                        MAX_HEADER_SPACING,
+                        spacing
+                    ));
+                }
+            }
+            if let Some(font_size) = block.font_size {
+                if !(1..=MAX_HEADER_FONT_SIZE).contains(&font_size) {
+                    problems.push(anyhow!(
+                        "{}.fontSize must be between 1 and {}, got {}",
+                        name,
+                        MAX_HEADER_FONT_SIZE,
+                        font_size
+                    ));
+                }
+            }
+        }
+    }
+
+    let global_options = global_options(ev);
+    let allowlist = allowlist();
+    problems.extend(
+        vec![
+            // flags of the request's own fields are always allowed
+            check_allowed(&ev.options, allowlist.as_deref()),
+            check_allowed(&ev.default_page_options, allowlist.as_deref()),
+            check_values(&global_options),
+            check_values(&ev.default_page_options),
+        ]
+        .into_iter()
+        .filter_map(Result::err),
+    );
+    problems
+}
 
-    let mut files = Vec::new();
+/// Assembles the wkhtmltopdf arguments for a request, except for the output path.
+pub fn build_args(ev: &PdfRequest, files: &mut TempFiles) -> anyhow::Result<Vec<String>> {
+    if let Some(problem) = field_problems(ev).into_iter().next() {
+        return Err(problem);
+    }
+    if let Some(proxy) = &ev.proxy {
+        info!("Using proxy {}", validate_proxy(proxy)?);
+    }
 
+    let global_options = global_options(ev);
+    let allowlist = allowlist();
+    let mut page_options = Vec::new();
     for page in &ev.pages {
-        args.push(page.page_type.to_string());
-        if page.page_type == PageType::TOC {
+        check_allowed(&page.options, allowlist.as_deref())?;
+        let options = own_page_options(ev, page)?;
+        check_values(&options)?;
+        page_options.push(dedupe(&merge_defaults(&ev.default_page_options, &options)));
+    }
+    let mut args = Vec::new();
+    push_options(
+        &mut args,
+        &without_overridden(&dedupe(&global_options), &page_options),
+    );
+    if ev.output_format.is_image() {
+        args.push("--format".to_owned());
+        args.push(ev.output_format.extension().to_owned());
+    }
+
+    // S3 sources are fetched up front, so that they can be downloaded concurrently
+    let s3_sources = ev
+        .pages
+        .iter()
+        .filter_map(|page| page.html_s3.as_ref())
+        .collect::<Vec<_>>();
+    let mut downloads = if files.is_dry_run() || s3_sources.is_empty() {
+        vec![Vec::new(); s3_sources.len()]
+    } else {
+        download_all(&s3_sources)?
+    }
+    .into_iter();
+    let downloads = ev
+        .pages
+        .iter()
+        .map(|page| page.html_s3.as_ref().and_then(|_| downloads.next()))
+        .collect::<Vec<_>>();
+
+    // wkhtmltopdf expects the cover first, then the table of contents and then the page bodies
+    let mut pages = ev
+        .pages
+        .iter()
+        .zip(page_options)
+        .zip(downloads)
+        .collect::<Vec<_>>();
+    pages.sort_by_key(|((page, _), _)| match page.page_type {
+        PageType::COVER => 0,
+        PageType::TOC => 1,
+        PageType::PAGE => 2,
+    });
+
+    for ((page, options), download) in pages {
+        if ev.output_format.is_image() {
+            // wkhtmltoimage has no page types and wants its options before the input
+            let mut page_args = Vec::new();
+            push_page_args(&mut page_args, ev, page, &options, download, files)?;
+            let input = page_args.remove(0);
+            args.extend(page_args);
+            args.push(input);
             continue;
         }
-        if let Some(ref html_url) = page.html_url {
-            args.push(html_url.clone());
-        } else if let Some(ref html_base64) = page.html_base64 {
-            let html = base64::decode(html_base64)
-                .map_err(|e| anyhow!("Failed to decode Base64: {}", e.to_string()))?;
-            let mut file = Builder::new()
-                .prefix("wkhtmltopdf-input")
-                .suffix(".html")
-                .tempfile()
-                .map_err(|e| anyhow!("Failed to create temp file: {}", e.to_string()))?;
-            file.write_all(&html)
-                .map_err(|e| anyhow!("Failed to write to temp file: {}", e.to_string()))?;
-            args.push(file.path().to_string_lossy().to_string());
-            files.push(file);
+        args.push(page.page_type.to_string());
+        if page.page_type == PageType::TOC {
+            push_toc_args(&mut args, page, &options, files)?;
         } else {
-            return Err(anyhow!("No page source specified"));
+            push_page_args(&mut args, ev, page, &options, download, files)?;
+        }
+    }
+
+    Ok(args)
+}
+
+/// wkhtmltoimage renders a single page and has none of the PDF-only features.
+fn check_image_request(ev: &PdfRequest) -> anyhow::Result<()> {
+    if ev.pages.len() != 1 {
+        return Err(anyhow!(
+            "Image output renders exactly one page, got {}",
+            ev.pages.len()
+        ));
+    }
+    let page = &ev.pages[0];
+    if page.header_html_base64.is_some()
+        || page.footer_html_base64.is_some()
+        || ev.header.is_some()
+        || ev.footer.is_some()
+    {
+        return Err(anyhow!("Headers and footers only apply to PDF output"));
+    }
+    if ev.grayscale || ev.low_quality || ev.no_pdf_compression {
+        return Err(anyhow!(
+            "grayscale, lowQuality and noPdfCompression only apply to PDF output"
+        ));
+    }
+    if ev.page_size.is_some()
+        || ev.orientation.is_some()
+        || ev.margins.is_some()
+        || ev.title.is_some()
+        || ev.dpi.is_some()
+    {
+        return Err(anyhow!(
+            "pageSize, orientation, margins, title and dpi only apply to PDF output"
+        ));
+    }
+    Ok(())
+}
+
+/// The request-level options, plus the flags of the convenience toggles and the page layout
+/// fields unless already present.
+fn global_options(ev: &PdfRequest) -> Vec<PdfOption> {
+    let mut options = ev.options.clone();
+    for (enabled, flag) in &[
+        (ev.grayscale, "--grayscale"),
+        (ev.low_quality, "--lowquality"),
+        (ev.no_pdf_compression, "--no-pdf-compression"),
+        (ev.proxy_hostname_lookup, "--proxy-hostname-lookup"),
+    ] {
+        if *enabled && !options.iter().any(|option| option.name == *flag) {
+            options.push(PdfOption {
+                name: flag.to_string(),
+                value: None,
+            });
         }
-        for option in &page.options {
-            args.push(option.name.clone());
-            if let Some(value) = &option.value {
-                args.push(value.clone());
+    }
+    let margins = ev.margins.clone().unwrap_or_default();
+    let title = ev.title.clone().filter(|title| !title.is_empty());
+    let dpi = ev.dpi.map(|dpi| dpi.to_string());
+    for (flag, value) in &[
+        ("--proxy", &ev.proxy),
+        ("--title", &title),
+        ("--dpi", &dpi),
+        ("--page-size", &ev.page_size),
+        ("--orientation", &ev.orientation),
+        ("--margin-top", &margins.top),
+        ("--margin-bottom", &margins.bottom),
+        ("--margin-left", &margins.left),
+        ("--margin-right", &margins.right),
+    ] {
+        if let Some(value) = value {
+            if !options.iter().any(|option| option.name == *flag) {
+                options.push(PdfOption {
+                    name: flag.to_string(),
+                    value: Some(value.clone()),
+                });
             }
         }
-        if page.html_base64.is_some() {
-            args.push("--enable-local-file-access".to_string());
+    }
+    for (prefix, block) in &[("--header", &ev.header), ("--footer", &ev.footer)] {
+        if let Some(block) = block {
+            for (name, value) in &[
+                ("left", block.left.clone()),
+                ("center", block.center.clone()),
+                ("right", block.right.clone()),
+                ("font-size", block.font_size.map(|size| size.to_string())),
+                ("spacing", block.spacing.map(|spacing| spacing.to_string())),
+            ] {
+                let flag = format!("{}-{}", prefix, name);
+                if let Some(value) = value {
+                    if !options.iter().any(|option| option.name == flag) {
+                        options.push(PdfOption {
+                            name: flag,
+                            value: Some(value.clone()),
+                        });
+                    }
+                }
+            }
         }
     }
+    options
+}
 
-    Ok((args, files))
+/// A page's options, plus the flags of its JavaScript fields, `zoom`, `usePrintMediaType` and
+/// `ignoreLoadErrors` unless already present.
+fn own_page_options(ev: &PdfRequest, page: &PdfPage) -> anyhow::Result<Vec<PdfOption>> {
+    if page.disable_javascript && page.javascript_delay_ms.is_some() {
+        return Err(anyhow!(
+            "javascriptDelayMs can't be combined with disableJavascript"
+        ));
+    }
+    let mut options = page.options.clone();
+    // either flag in the page's options decides the media type
+    let has_media_type = options.iter().any(|option| {
+        option.name == "--print-media-type" || option.name == "--no-print-media-type"
+    });
+    let mut push = |name: &str, value: Option<String>| {
+        if !options.iter().any(|option| option.name == name) {
+            options.push(PdfOption {
+                name: name.to_owned(),
+                value,
+            });
+        }
+    };
+    if let Some(delay) = page.javascript_delay_ms {
+        push("--javascript-delay", Some(delay.to_string()));
+    }
+    if page.disable_javascript {
+        push("--disable-javascript", None);
+    }
+    if let Some(zoom) = ev.zoom {
+        push("--zoom", Some(zoom.to_string()));
+    }
+    if !has_media_type {
+        match page.use_print_media_type.or(ev.use_print_media_type) {
+            Some(true) => push("--print-media-type", None),
+            Some(false) => push("--no-print-media-type", None),
+            None => {}
+        }
+    }
+    let policy = match ev.page_error_policy {
+        Some(policy) => Some(policy),
+        None if ev.ignore_load_errors => Some(PageErrorPolicy::Ignore),
+        None => None,
+    };
+    if let Some(policy) = policy.filter(|_| page.page_type != PageType::TOC) {
+        push("--load-error-handling", Some(policy.handling().to_owned()));
+        push(
+            "--load-media-error-handling",
+            Some(policy.handling().to_owned()),
+        );
+    }
+    Ok(options)
+}
+
+fn push_toc_args(
+    args: &mut Vec<String>,
+    page: &PdfPage,
+    options: &[PdfOption],
+    files: &mut TempFiles,
+) -> anyhow::Result<()> {
+    push_options(args, options);
+    if let Some(ref xsl_base64) = page.xsl_base64 {
+        let xsl = decode_base64(xsl_base64)?;
+        let path = files.write("wkhtmltopdf-toc", ".xsl", &xsl)?;
+        args.push("--xsl-style-sheet".to_owned());
+        args.push(path.to_string_lossy().to_string());
+    }
+    Ok(())
 }
 
-fn upload(file: &mut NamedTempFile, s3_details: &S3Details) -> anyhow::Result<PutObjectOutput> {
-    let region = if let Ok(endpoint) = std::env::var("S3_ENDPOINT") {
-        let region = Region::Custom {
-            name: "us-east-1".to_owned(),
-            endpoint: endpoint.to_owned(),
+fn push_page_args(
+    args: &mut Vec<String>,
+    ev: &PdfRequest,
+    page: &PdfPage,
+    options: &[PdfOption],
+    download: Option<Vec<u8>>,
+    files: &mut TempFiles,
+) -> anyhow::Result<()> {
+    if page.xsl_base64.is_some() {
+        return Err(anyhow!("XSL style sheets are only supported on TOC pages"));
+    }
+    let sources = [
+        ("htmlUrl", page.html_url.is_some()),
+        ("htmlBase64", page.html_base64.is_some()),
+        ("htmlS3", page.html_s3.is_some()),
+        ("markdownBase64", page.markdown_base64.is_some()),
+    ]
+    .iter()
+    .filter(|(_, specified)| *specified)
+    .map(|(name, _)| *name)
+    .collect::<Vec<_>>();
+    if sources.len() > 1 {
+        return Err(anyhow!(
+            "Conflicting page sources specified: {}",
+            sources.join(", ")
+        ));
+    }
+
+    if page.html_encoding != HtmlEncoding::Plain && page.html_base64.is_none() {
+        return Err(anyhow!("htmlEncoding only applies to htmlBase64"));
+    }
+    let html = if let Some(ref html_url) = page.html_url {
+        validate_url(html_url, ev.allow_private_urls)?;
+        args.push(html_url.clone());
+        None
+    } else if let Some(ref html_base64) = page.html_base64 {
+        let html = decode_base64(html_base64)?;
+        match page.html_encoding {
+            HtmlEncoding::Plain => Some(html),
+            HtmlEncoding::Gzip => Some(gunzip(&html)?),
+        }
+    } else if page.html_s3.is_some() {
+        if let Some(ref download) = download {
+            check_input_size(download.len())?;
+        }
+        download
+    } else if let Some(ref markdown_base64) = page.markdown_base64 {
+        let markdown = String::from_utf8(decode_base64(markdown_base64)?)
+            .map_err(|e| anyhow!("Markdown is not valid UTF-8: {}", e.to_string()))?;
+        let css = match ev.markdown_css_base64 {
+            Some(ref css_base64) => Some(
+                String::from_utf8(decode_base64(css_base64)?)
+                    .map_err(|e| anyhow!("Markdown CSS is not valid UTF-8: {}", e.to_string()))?,
+            ),
+            None => None,
         };
-        info!(
-            "Picked up non-standard endpoint {:?} from S3_ENDPOINT env var",
-            region
-        );
-        region
-    } else if let Some(region) = &s3_details.region {
-        Region::from_str(region.as_str())?
+        Some(markdown_to_html(&markdown, css.as_deref()).into_bytes())
     } else {
-        Region::ApSoutheast2
+        return Err(anyhow!("No page source specified"));
     };
+    let html = match html {
+        Some(html) if ev.sanitize_html => {
+            let html = String::from_utf8(html)
+                .map_err(|e| anyhow!("HTML to sanitize is not valid UTF-8: {}", e.to_string()))?;
+            Some(html::sanitize(&html, &ev.sanitize_allowed_schemes).into_bytes())
+        }
+        html => html,
+    };
+    let html = match html {
+        Some(html) if !page.inline_images.is_empty() => {
+            Some(inline_images(html, &page.inline_images)?)
+        }
+        None if !page.inline_images.is_empty() => {
+            return Err(anyhow!("inlineImages requires a local page source"));
+        }
+        html => html,
+    };
+    let assets_dir = match page.assets_zip_base64 {
+        Some(ref assets_zip_base64) => {
+            if html.is_none() {
+                return Err(anyhow!("assetsZipBase64 requires a local page source"));
+            }
+            let dir = files.create_dir("wkhtmltopdf-assets")?;
+            if !files.is_dry_run() {
+                extract_assets(&decode_base64(assets_zip_base64)?, &dir)?;
+            }
+            Some(dir)
+        }
+        None => None,
+    };
+    // the temp files wkhtmltopdf has to read, see `push_file_access`
+    let mut local_files = Vec::new();
+    if let Some(ref html) = html {
+        let path = files.write_in(assets_dir.as_deref(), "wkhtmltopdf-input", ".html", html)?;
+        args.push(path.to_string_lossy().to_string());
+        local_files.push(path);
+    }
 
-    let mut contents = Vec::new();
-    let length = file.read_to_end(&mut contents)?;
-    if length == 0 {
+    push_options(args, options);
+    let mut custom_headers = page.custom_headers.clone();
+    // local HTML isn't fetched, so the user agent only matters to URL pages
+    if let (Some(user_agent), Some(_)) = (&ev.user_agent, &page.html_url) {
+        if !custom_headers
+            .iter()
+            .any(|(name, _)| name.eq_ignore_ascii_case("User-Agent"))
+        {
+            custom_headers.push(("User-Agent".to_owned(), user_agent.clone()));
+        }
+    }
+    for (name, value) in &custom_headers {
+        args.push("--custom-header".to_owned());
+        args.push(name.clone());
+        args.push(value.clone());
+    }
+    if !custom_headers.is_empty() {
+        args.push("--custom-header-propagation".to_owned());
+    }
+    if !page.cookies.is_empty() && page.html_url.is_none() {
+        return Err(anyhow!("Cookies are only supported on htmlUrl pages"));
+    }
+    for (name, value) in &page.cookies {
+        if name.is_empty()
+            || name
+                .chars()
+                .any(|c| c.is_whitespace() || c.is_control() || c == '=' || c == ';')
+        {
+            return Err(anyhow!("Invalid cookie name {:?}", name));
+        }
+        args.push("--cookie".to_owned());
+        args.push(name.clone());
+        args.push(value.clone());
+    }
+    if let Some(css_base64) = page
+        .inject_css_base64
+        .as_ref()
+        .or(ev.inject_css_base64.as_ref())
+    {
+        let css = decode_base64(css_base64)?;
+        let path = files.write("wkhtmltopdf-style", ".css", &css)?;
+        args.push("--user-style-sheet".to_owned());
+        args.push(path.to_string_lossy().to_string());
+        local_files.push(path);
+    }
+    for (flag, html_base64) in &[
+        ("--header-html", &page.header_html_base64),
+        ("--footer-html", &page.footer_html_base64),
+    ] {
+        if let Some(html_base64) = html_base64 {
+            let html = decode_base64(html_base64)?;
+            let path = files.write("wkhtmltopdf-header-footer", ".html", &html)?;
+            args.push(flag.to_string());
+            args.push(path.to_string_lossy().to_string());
+            local_files.push(path);
+        }
+    }
+    push_file_access(args, ev, &local_files);
+
+    Ok(())
+}
+
+/// Replaces `src="name"` and `src='name'` of each image with a `data:` URI. Images which aren't
+/// referenced are skipped with a warning. The resulting HTML is subject to `MAX_HTML_BYTES`.
+fn inline_images(html: Vec<u8>, images: &[InlineImage]) -> anyhow::Result<Vec<u8>> {
+    let mut html = String::from_utf8(html).map_err(|e| {
+        anyhow!(
+            "HTML with inlineImages is not valid UTF-8: {}",
+            e.to_string()
+        )
+    })?;
+    for image in images {
+        if !image.mime.starts_with("image/") || image.mime.contains(['"', '\'', ';']) {
+            return Err(anyhow!(
+                "Invalid MIME type {:?} of inline image {}",
+                image.mime,
+                image.name
+            ));
+        }
+        let patterns = [
+            format!("src=\"{}\"", image.name),
+            format!("src='{}'", image.name),
+        ];
+        if !patterns
+            .iter()
+            .any(|pattern| html.contains(pattern.as_str()))
+        {
+            warn!("Inline image {} is not referenced by the page", image.name);
+            continue;
+        }
+        // re-encoded, so that whitespace or other junk can't break out of the attribute
+        let uri = format!(
+            "data:{};base64,{}",
+            image.mime,
+            base64::encode(decode_base64(&image.base64)?)
+        );
+        for pattern in &patterns {
+            html = html.replace(pattern.as_str(), &format!("src=\"{}\"", uri));
+        }
+        check_input_size(html.len())?;
+    }
+    Ok(html.into_bytes())
+}
+
+/// Lets wkhtmltopdf read our temp files, either with blanket local file access or, with
+/// `scopedFileAccess`, with an `--allow` for each directory they're in.
+fn push_file_access(args: &mut Vec<String>, ev: &PdfRequest, local_files: &[PathBuf]) {
+    if local_files.is_empty() {
+        return;
+    }
+    if !ev.scoped_file_access {
+        args.push("--enable-local-file-access".to_string());
+        return;
+    }
+    let mut dirs = Vec::new();
+    for dir in local_files.iter().filter_map(|path| path.parent()) {
+        // placeholders of dry runs have no directory
+        if !dir.as_os_str().is_empty() && !dirs.contains(&dir) {
+            dirs.push(dir);
+        }
+    }
+    for dir in dirs {
+        args.push("--allow".to_owned());
+        args.push(dir.to_string_lossy().to_string());
+    }
+}
+
+/// Unpacks a zip of page assets into `dir`, refusing entries that would land outside of it.
+fn extract_assets(zip: &[u8], dir: &Path) -> anyhow::Result<()> {
+    let mut archive = zip::ZipArchive::new(std::io::Cursor::new(zip))
+        .map_err(|e| anyhow!("Failed to open assets zip: {}", e.to_string()))?;
+    // guard against zip bombs, the declared sizes are enforced while extracting
+    let mut total_size = 0;
+    for i in 0..archive.len() {
+        let entry = archive
+            .by_index(i)
+            .map_err(|e| anyhow!("Failed to read assets zip: {}", e.to_string()))?;
+        total_size += entry.size() as usize;
+    }
+    check_input_size(total_size)?;
+    for i in 0..archive.len() {
+        let entry = archive
+            .by_index(i)
+            .map_err(|e| anyhow!("Failed to read assets zip: {}", e.to_string()))?;
+        let name = entry.name().to_owned();
+        let relative = Path::new(&name);
+        if !relative
+            .components()
+            .all(|c| matches!(c, Component::Normal(_) | Component::CurDir))
+        {
+            return Err(anyhow!(
+                "Assets zip entry {} escapes the assets directory",
+                name
+            ));
+        }
+        let target = dir.join(relative);
+        if entry.is_dir() {
+            fs::create_dir_all(&target)
+        } else {
+            if let Some(parent) = target.parent() {
+                fs::create_dir_all(parent)
+                    .map_err(|e| anyhow!("Failed to extract asset {}: {}", name, e.to_string()))?;
+            }
+            let size = entry.size();
+            fs::File::create(&target)
+                .and_then(|mut file| std::io::copy(&mut entry.take(size), &mut file).map(|_| ()))
+        }
+        .map_err(|e| anyhow!("Failed to extract asset {}: {}", name, e.to_string()))?;
+    }
+    Ok(())
+}
+
+fn push_options(args: &mut Vec<String>, options: &[PdfOption]) {
+    for option in options {
+        args.push(option.name.clone());
+        if let Some(value) = &option.value {
+            args.push(value.clone());
+        }
+    }
+}
+
+/// Decodes a Base64 field, refusing anything larger than `MAX_HTML_BYTES` before allocating it.
+fn decode_base64(contents: &str) -> anyhow::Result<Vec<u8>> {
+    let padding = contents.bytes().rev().take_while(|&b| b == b'=').count();
+    check_input_size((contents.len() * 3 / 4).saturating_sub(padding))?;
+    base64::decode(contents).map_err(|e| anyhow!("Failed to decode Base64: {}", e.to_string()))
+}
+
+fn max_input_bytes() -> anyhow::Result<usize> {
+    match env::var("MAX_HTML_BYTES") {
+        Ok(max_bytes) => max_bytes
+            .parse::<usize>()
+            .map_err(|e| anyhow!("Invalid MAX_HTML_BYTES: {}", e.to_string())),
+        Err(_) => Ok(DEFAULT_MAX_HTML_BYTES),
+    }
+}
+
+fn check_input_size(length: usize) -> anyhow::Result<()> {
+    let max_bytes = max_input_bytes()?;
+    if length > max_bytes {
+        return Err(anyhow!(
+            "Input of {} bytes exceeds the limit of {} bytes",
+            length,
+            max_bytes
+        ));
+    }
+    Ok(())
+}
+
+/// Points out that the PDF itself was fine, so it's only the upload that needs fixing.
+fn upload_failed(error: anyhow::Error) -> anyhow::Error {
+    anyhow!("Rendered successfully, but the upload failed: {}", error)
+}
+
+fn read_output(path: &Path) -> anyhow::Result<Vec<u8>> {
+    let contents = fs::read(path)?;
+    if contents.is_empty() {
         return Err(anyhow!("Failed to read PDF output"));
     }
-    let put_request = PutObjectRequest {
-        bucket: s3_details.bucket.clone(),
-        key: s3_details.object_key.clone(),
-        content_type: Some("application/pdf".to_owned()),
-        body: Some(contents.into()),
-        ..Default::default()
+    Ok(contents)
+}
+
+/// Decompresses at most `MAX_HTML_BYTES`, so that a small gzip bomb can't fill up the memory.
+fn gunzip(contents: &[u8]) -> anyhow::Result<Vec<u8>> {
+    let max_bytes = max_input_bytes()?;
+    let mut html = Vec::new();
+    GzDecoder::new(contents)
+        .take(max_bytes as u64 + 1)
+        .read_to_end(&mut html)
+        .map_err(|e| anyhow!("Failed to decompress gzipped HTML: {}", e.to_string()))?;
+    check_input_size(html.len())?;
+    Ok(html)
+}
+
+fn gzip(contents: &[u8]) -> anyhow::Result<Vec<u8>> {
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+    encoder
+        .write_all(contents)
+        .and_then(|_| encoder.finish())
+        .map_err(|e| anyhow!("Failed to compress PDF: {}", e.to_string()))
+}
+
+fn encode_inline(contents: &[u8]) -> anyhow::Result<String> {
+    let max_bytes = match env::var("INLINE_MAX_BYTES") {
+        Ok(max_bytes) => max_bytes
+            .parse::<usize>()
+            .map_err(|e| anyhow!("Invalid INLINE_MAX_BYTES: {}", e.to_string()))?,
+        Err(_) => DEFAULT_INLINE_MAX_BYTES,
     };
 
-    let s3 = S3Client::new(region);
-    let mut runtime = tokio::runtime::Runtime::new()?;
-    let put_response = runtime.block_on(s3.put_object(put_request))?;
-    info!(
-        "Uploaded PDF to s3://{}/{}",
-        s3_details.bucket, s3_details.object_key
-    );
+    let length = contents.len();
+    let encoded_length = length.div_ceil(3) * 4;
+    if encoded_length > max_bytes {
+        return Err(anyhow!(
+            "PDF is too large to be returned inline ({} bytes encoded, limit is {} bytes), specify an S3 output instead",
+            encoded_length,
+            max_bytes
+        ));
+    }
+    info!("Returning {} bytes of PDF inline", length);
 
-    Ok(put_response)
+    Ok(base64::encode(contents))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::{json, Value};
+
+    fn request(request: Value) -> PdfRequest {
+        serde_json::from_value(request).unwrap()
+    }
+
+    fn html_page(html: &str) -> Value {
+        json!({"type": "PAGE", "htmlBase64": base64::encode(html)})
+    }
+
+    #[test]
+    fn temp_files_are_removed_after_a_failed_build() {
+        let ev = request(json!({
+            "pages": [html_page("<p>one</p>"), {"type": "PAGE", "htmlBase64": "not base64!"}],
+        }));
+        let mut files = TempFiles::new(false);
+        assert!(build_args(&ev, &mut files).is_err());
+        let paths = files
+            .files
+            .iter()
+            .map(|file| file.path().to_owned())
+            .collect::<Vec<_>>();
+        assert_eq!(paths.len(), 1);
+        assert!(paths[0].exists());
+        drop(files);
+        assert!(paths.iter().all(|path| !path.exists()));
+    }
+
+    fn args(ev: &PdfRequest) -> anyhow::Result<Vec<String>> {
+        build_args(ev, &mut TempFiles::new(true))
+    }
+
+    #[test]
+    fn pages_need_exactly_one_source() {
+        let none = request(json!({"pages": [{"type": "PAGE"}]}));
+        assert_eq!(
+            args(&none).unwrap_err().to_string(),
+            "No page source specified"
+        );
+
+        let one = request(json!({"pages": [html_page("<p>one</p>")]}));
+        assert_eq!(
+            args(&one).unwrap(),
+            vec![
+                "page",
+                "<wkhtmltopdf-input-0.html>",
+                "--enable-local-file-access"
+            ]
+        );
+
+        let several = request(json!({"pages": [{
+            "type": "PAGE",
+            "htmlUrl": "https://93.184.216.34/",
+            "htmlBase64": base64::encode("<p>one</p>"),
+            "markdownBase64": base64::encode("# one"),
+        }]}));
+        assert_eq!(
+            args(&several).unwrap_err().to_string(),
+            "Conflicting page sources specified: htmlUrl, htmlBase64, markdownBase64"
+        );
+    }
+
+    #[test]
+    fn dry_run_previews_command() {
+        let ev = request(json!({
+            "dryRun": true,
+            "grayscale": true,
+            "pages": [{"type": "PAGE", "htmlUrl": "https://93.184.216.34/"}],
+        }));
+        let response = convert(ev, lambda_runtime::Context::default()).unwrap();
+        assert!(response.success);
+        assert_eq!(
+            response.command_preview.unwrap(),
+            vec![
+                "--grayscale",
+                "page",
+                "https://93.184.216.34/",
+                "<wkhtmltopdf-output-0.pdf>"
+            ]
+        );
+    }
+
+    #[test]
+    fn cover_and_toc_come_before_pages() {
+        let ev = request(json!({"pages": [
+            {"type": "PAGE", "htmlUrl": "https://93.184.216.34/body"},
+            {"type": "TOC"},
+            {"type": "COVER", "htmlUrl": "https://93.184.216.34/cover"},
+        ]}));
+        assert_eq!(
+            args(&ev).unwrap(),
+            vec![
+                "cover",
+                "https://93.184.216.34/cover",
+                "toc",
+                "page",
+                "https://93.184.216.34/body"
+            ]
+        );
+    }
+
+    #[test]
+    fn user_style_sheet_follows_each_input() {
+        let ev = request(json!({
+            "injectCssBase64": base64::encode("body { color: red }"),
+            "pages": [
+                {"type": "PAGE", "htmlUrl": "https://93.184.216.34/"},
+                {
+                    "type": "PAGE",
+                    "htmlBase64": base64::encode("<p>two</p>"),
+                    "injectCssBase64": base64::encode("body { color: blue }"),
+                    "options": [{"name": "--zoom", "value": "0.8"}],
+                },
+            ],
+        }));
+        assert_eq!(
+            args(&ev).unwrap(),
+            vec![
+                "page",
+                "https://93.184.216.34/",
+                "--user-style-sheet",
+                "<wkhtmltopdf-style-0.css>",
+                "--enable-local-file-access",
+                "page",
+                "<wkhtmltopdf-input-1.html>",
+                "--zoom",
+                "0.8",
+                "--user-style-sheet",
+                "<wkhtmltopdf-style-2.css>",
+                "--enable-local-file-access"
+            ]
+        );
+    }
+
+    #[test]
+    fn decoded_input_is_capped_at_the_limit() {
+        let at_limit = base64::encode(vec![0; DEFAULT_MAX_HTML_BYTES]);
+        assert_eq!(
+            decode_base64(&at_limit).unwrap().len(),
+            DEFAULT_MAX_HTML_BYTES
+        );
+        let over_limit = base64::encode(vec![0; DEFAULT_MAX_HTML_BYTES + 1]);
+        assert_eq!(
+            decode_base64(&over_limit).unwrap_err().to_string(),
+            format!(
+                "Input of {} bytes exceeds the limit of {} bytes",
+                DEFAULT_MAX_HTML_BYTES + 1,
+                DEFAULT_MAX_HTML_BYTES
+            )
+        );
+    }
+
+    #[test]
+    fn requests_need_a_body_page() {
+        let message =
+            "At least one page of type PAGE is required, a cover or TOC alone has no content";
+        for pages in &[
+            json!([]),
+            json!([{"type": "TOC"}]),
+            json!([{"type": "COVER", "htmlUrl": "https://93.184.216.34/"}]),
+        ] {
+            let ev = request(json!({ "pages": pages }));
+            let problems = field_problems(&ev)
+                .iter()
+                .map(ToString::to_string)
+                .collect::<Vec<_>>();
+            assert_eq!(problems, vec![message]);
+            assert_eq!(args(&ev).unwrap_err().to_string(), message);
+        }
+    }
+
+    #[test]
+    fn layout_fields_are_lowered_to_flags() {
+        let ev = request(json!({
+            "pageSize": "A4",
+            "orientation": "Landscape",
+            "margins": {"top": "10mm", "left": "0.5in"},
+            "options": [{"name": "--orientation", "value": "Portrait"}],
+            "pages": [{"type": "PAGE", "htmlUrl": "https://93.184.216.34/"}],
+        }));
+        assert_eq!(
+            args(&ev).unwrap(),
+            vec![
+                "--orientation",
+                "Portrait",
+                "--page-size",
+                "A4",
+                "--margin-top",
+                "10mm",
+                "--margin-left",
+                "0.5in",
+                "page",
+                "https://93.184.216.34/"
+            ]
+        );
+
+        let invalid = request(json!({
+            "margins": {"top": "10 furlongs"},
+            "pages": [{"type": "PAGE", "htmlUrl": "https://93.184.216.34/"}],
+        }));
+        assert_eq!(
+            args(&invalid).unwrap_err().to_string(),
+            "Invalid value 10 furlongs for option --margin-top"
+        );
+    }
+
+    #[test]
+    fn user_agent_follows_url_pages_only() {
+        let ev = request(json!({
+            "userAgent": "Mozilla/5.0 (Renderer)",
+            "pages": [
+                {
+                    "type": "PAGE",
+                    "htmlUrl": "https://93.184.216.34/",
+                    "options": [{"name": "--zoom", "value": "0.8"}],
+                },
+                html_page("<p>local</p>"),
+            ],
+        }));
+        assert_eq!(
+            args(&ev).unwrap(),
+            vec![
+                "page",
+                "https://93.184.216.34/",
+                "--zoom",
+                "0.8",
+                "--custom-header",
+                "User-Agent",
+                "Mozilla/5.0 (Renderer)",
+                "--custom-header-propagation",
+                "page",
+                "<wkhtmltopdf-input-0.html>",
+                "--enable-local-file-access"
+            ]
+        );
+    }
+
+    #[test]
+    fn print_media_type_states() {
+        let page_args = |request_value: Value, page_value: Value| {
+            let mut page = json!({"type": "PAGE", "htmlUrl": "https://93.184.216.34/"});
+            if !page_value.is_null() {
+                page["usePrintMediaType"] = page_value;
+            }
+            let mut ev = json!({ "pages": [page] });
+            if !request_value.is_null() {
+                ev["usePrintMediaType"] = request_value;
+            }
+            args(&request(ev)).unwrap()
+        };
+        assert_eq!(
+            page_args(json!(true), Value::Null),
+            vec!["page", "https://93.184.216.34/", "--print-media-type"]
+        );
+        assert_eq!(
+            page_args(json!(false), Value::Null),
+            vec!["page", "https://93.184.216.34/", "--no-print-media-type"]
+        );
+        assert_eq!(
+            page_args(Value::Null, Value::Null),
+            vec!["page", "https://93.184.216.34/"]
+        );
+        assert_eq!(
+            page_args(json!(true), json!(false)),
+            vec!["page", "https://93.184.216.34/", "--no-print-media-type"]
+        );
+    }
+
+    #[test]
+    fn header_and_footer_blocks_are_lowered_in_order() {
+        let ev = request(json!({
+            "pages": [html_page("<p>a</p>")],
+            "options": [{"name": "--footer-right", "value": "raw"}],
+            "header": {
+                "left": "[title]",
+                "center": "[section]",
+                "right": "[page] of [topage]",
+                "fontSize": 9,
+                "spacing": 2.5
+            },
+            "footer": {"center": "[date]", "right": "[page]"}
+        }));
+        assert_eq!(
+            args(&ev).unwrap(),
+            vec![
+                "--footer-right",
+                "raw",
+                "--header-left",
+                "[title]",
+                "--header-center",
+                "[section]",
+                "--header-right",
+                "[page] of [topage]",
+                "--header-font-size",
+                "9",
+                "--header-spacing",
+                "2.5",
+                "--footer-center",
+                "[date]",
+                "page",
+                "<wkhtmltopdf-input-0.html>",
+                "--enable-local-file-access",
+            ]
+        );
+    }
 }
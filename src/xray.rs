@@ -0,0 +1,115 @@
+use rand::Rng;
+use serde_json::{json, Map, Value};
+use std::env;
+use std::net::{SocketAddr, UdpSocket};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+#[allow(unused_imports)]
+use crate::{debug, error, info, warn};
+
+const HEADER: &str = "{\"format\": \"json\", \"version\": 1}\n";
+
+/// An X-Ray subsegment which is sent to the daemon when dropped. Without AWS_XRAY_DAEMON_ADDRESS
+/// or a sampled trace header, it's inert.
+pub struct Subsegment {
+    inner: Option<Inner>,
+}
+
+struct Inner {
+    daemon: SocketAddr,
+    trace_id: String,
+    parent_id: String,
+    id: String,
+    name: String,
+    start_time: f64,
+    annotations: Map<String, Value>,
+}
+
+impl Subsegment {
+    /// `trace_header` is the invocation's `Lambda-Runtime-Trace-Id`, falling back to
+    /// _X_AMZN_TRACE_ID.
+    pub fn start(name: &str, trace_header: Option<&str>) -> Self {
+        Subsegment {
+            inner: Inner::new(name, trace_header),
+        }
+    }
+
+    pub fn annotate(&mut self, key: &str, value: impl Into<Value>) {
+        if let Some(inner) = &mut self.inner {
+            inner.annotations.insert(key.to_owned(), value.into());
+        }
+    }
+}
+
+impl Inner {
+    fn new(name: &str, trace_header: Option<&str>) -> Option<Self> {
+        let daemon = env::var("AWS_XRAY_DAEMON_ADDRESS").ok()?;
+        let daemon = match daemon.parse::<SocketAddr>() {
+            Ok(daemon) => daemon,
+            Err(e) => {
+                debug!("Invalid AWS_XRAY_DAEMON_ADDRESS {}: {}", daemon, e);
+                return None;
+            }
+        };
+        let header = trace_header
+            .map(str::to_owned)
+            .or_else(|| env::var("_X_AMZN_TRACE_ID").ok())?;
+        let mut trace_id = None;
+        let mut parent_id = None;
+        let mut sampled = false;
+        for field in header.split(';') {
+            let mut parts = field.splitn(2, '=');
+            match (parts.next(), parts.next()) {
+                (Some("Root"), Some(value)) => trace_id = Some(value.to_owned()),
+                (Some("Parent"), Some(value)) => parent_id = Some(value.to_owned()),
+                (Some("Sampled"), Some(value)) => sampled = value == "1",
+                _ => {}
+            }
+        }
+        if !sampled {
+            return None;
+        }
+        Some(Inner {
+            daemon,
+            trace_id: trace_id?,
+            parent_id: parent_id?,
+            id: format!("{:016x}", rand::thread_rng().gen::<u64>()),
+            name: name.to_owned(),
+            start_time: now(),
+            annotations: Map::new(),
+        })
+    }
+
+    fn send(&mut self) -> std::io::Result<()> {
+        let document = json!({
+            "name": self.name,
+            "id": self.id,
+            "trace_id": self.trace_id,
+            "parent_id": self.parent_id,
+            "type": "subsegment",
+            "start_time": self.start_time,
+            "end_time": now(),
+            "annotations": self.annotations,
+        });
+        let socket = UdpSocket::bind("0.0.0.0:0")?;
+        socket.send_to(format!("{}{}", HEADER, document).as_bytes(), self.daemon)?;
+        Ok(())
+    }
+}
+
+impl Drop for Subsegment {
+    fn drop(&mut self) {
+        if let Some(inner) = &mut self.inner {
+            if let Err(e) = inner.send() {
+                warn!("Failed to send X-Ray subsegment {}: {}", inner.name, e);
+            }
+        }
+    }
+}
+
+fn now() -> f64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs_f64())
+        .unwrap_or_default()
+}